@@ -0,0 +1,22 @@
+CREATE TABLE post_versions (
+    id            INTEGER     GENERATED ALWAYS AS IDENTITY,
+    post_id       INTEGER     NOT NULL,
+    editor_id     UUID, /* NULL = Anonymous or deleted user */
+    edited_at     TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    tags_added    TEXT[]      NOT NULL DEFAULT '{}',
+    tags_removed  TEXT[]      NOT NULL DEFAULT '{}',
+    pools_added   INTEGER[]   NOT NULL DEFAULT '{}',
+    pools_removed INTEGER[]   NOT NULL DEFAULT '{}',
+    /* Both NULL = source unchanged */
+    old_source    TEXT,
+    new_source    TEXT,
+    /* The version this edit undid, if it was a revert */
+    reverts       INTEGER,
+
+    PRIMARY KEY (id),
+    FOREIGN KEY (post_id)   REFERENCES posts ON DELETE CASCADE,
+    FOREIGN KEY (editor_id) REFERENCES users ON DELETE SET NULL,
+    FOREIGN KEY (reverts)   REFERENCES post_versions ON DELETE SET NULL
+);
+
+CREATE INDEX post_versions_post_id ON post_versions (post_id);
@@ -0,0 +1,8 @@
+CREATE TYPE RATING AS ENUM ('safe', 'questionable', 'explicit');
+
+ALTER TABLE posts ADD COLUMN rating RATING NOT NULL DEFAULT 'safe';
+
+ALTER TABLE post_versions
+    /* Both NULL = rating unchanged */
+    ADD COLUMN old_rating RATING,
+    ADD COLUMN new_rating RATING;
@@ -0,0 +1,16 @@
+CREATE TYPE THEME AS ENUM ('light', 'dark');
+CREATE TYPE LAYOUT AS ENUM ('masonry', 'grid');
+
+/* Users without a row here use the defaults in extractors::Settings */
+CREATE TABLE user_settings (
+    user_id        UUID    NOT NULL,
+    page_size      INTEGER NOT NULL,
+    max_rating     RATING  NOT NULL,
+    blacklist      TEXT[]  NOT NULL,
+    thumbnail_size INTEGER NOT NULL,
+    theme          THEME   NOT NULL,
+    layout         LAYOUT  NOT NULL,
+
+    PRIMARY KEY (user_id),
+    FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE
+);
@@ -0,0 +1,17 @@
+/* Posts having every tag of at least one blacklist expression. Expressions are
+   passed flattened, tag n belonging to expression n. */
+CREATE FUNCTION blacklisted_posts(blacklist_expressions INTEGER[], blacklist_tags TEXT[])
+RETURNS TABLE (post_id INTEGER)
+LANGUAGE SQL STABLE
+AS $$
+    WITH blacklist AS (
+        SELECT DISTINCT expression, tag
+        FROM UNNEST(blacklist_expressions, blacklist_tags) AS b(expression, tag)
+    )
+    SELECT DISTINCT pt.post_id
+    FROM blacklist b
+    JOIN tags t ON t.name = b.tag
+    JOIN post_tags pt ON pt.tag_id = t.id
+    GROUP BY pt.post_id, b.expression
+    HAVING COUNT(*) = (SELECT COUNT(*) FROM blacklist b2 WHERE b2.expression = b.expression);
+$$;
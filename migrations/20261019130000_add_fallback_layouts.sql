@@ -0,0 +1,15 @@
+ALTER TYPE LAYOUT ADD VALUE 'justified';
+ALTER TYPE LAYOUT ADD VALUE 'columns';
+
+/* Mirrors posts::scale_frame: the largest size fitting in a bound x bound box
+   that keeps the original aspect ratio */
+CREATE FUNCTION thumbnail_dimensions(
+    width INTEGER, height INTEGER, bound INTEGER,
+    OUT thumbnail_width INTEGER, OUT thumbnail_height INTEGER)
+LANGUAGE SQL IMMUTABLE
+AS $$
+    SELECT FLOOR(width * ratio)::INTEGER, FLOOR(height * ratio)::INTEGER
+    FROM (
+        SELECT LEAST(bound::REAL / GREATEST(width, 1), bound::REAL / GREATEST(height, 1)) AS ratio
+    ) AS r;
+$$;
@@ -0,0 +1,8 @@
+ALTER TABLE sessions
+    /* A non-secret handle for showing and revoking sessions */
+    ADD COLUMN id           INTEGER     GENERATED ALWAYS AS IDENTITY UNIQUE,
+    ADD COLUMN last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ADD COLUMN user_agent   TEXT        NOT NULL DEFAULT '',
+    ADD COLUMN ip           TEXT        NOT NULL DEFAULT '';
+
+CREATE INDEX sessions_user_id ON sessions (user_id);
@@ -0,0 +1,5 @@
+/* Accounts created through registration are put in this group. Previously
+   registering failed outright since users.group_id has no default. */
+INSERT INTO groups (name, description)
+VALUES ('users', 'Registered users')
+ON CONFLICT (name) DO NOTHING;
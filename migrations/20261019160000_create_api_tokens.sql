@@ -0,0 +1,27 @@
+CREATE TABLE api_tokens (
+    id           INTEGER     GENERATED ALWAYS AS IDENTITY,
+    user_id      UUID        NOT NULL,
+    name         TEXT        NOT NULL,
+    /* SHA-256 of the token, hex encoded. The token itself is never stored */
+    hash         TEXT        NOT NULL,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMPTZ,
+    last_used_ip TEXT,
+
+    PRIMARY KEY (id),
+    FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE,
+    UNIQUE (hash),
+    CONSTRAINT non_empty_name CHECK (name <> '')
+);
+
+CREATE INDEX api_tokens_user_id ON api_tokens (user_id);
+
+/* A token can only do what its owner can, and then only these */
+CREATE TABLE api_token_scopes (
+    token_id  INTEGER   NOT NULL,
+    operation OPERATION NOT NULL,
+    resource  RESOURCE  NOT NULL,
+
+    FOREIGN KEY (token_id) REFERENCES api_tokens ON DELETE CASCADE,
+    UNIQUE (token_id, operation, resource)
+);
@@ -0,0 +1,32 @@
+/* Tells running servers to drop cached authentication information when
+   permissions, groups or group membership change, however they're changed */
+CREATE FUNCTION notify_auth_change() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_TABLE_NAME = 'permissions' THEN
+        IF TG_OP <> 'INSERT' THEN
+            PERFORM pg_notify('auth_changes', 'group:' || COALESCE(OLD.group_id::TEXT, ''));
+        END IF;
+        IF TG_OP <> 'DELETE' THEN
+            PERFORM pg_notify('auth_changes', 'group:' || COALESCE(NEW.group_id::TEXT, ''));
+        END IF;
+    ELSIF TG_TABLE_NAME = 'groups' THEN
+        PERFORM pg_notify('auth_changes', 'group:' || OLD.id);
+    ELSIF TG_TABLE_NAME = 'users' THEN
+        PERFORM pg_notify('auth_changes', 'user:' || OLD.id);
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER permissions_auth_change
+AFTER INSERT OR UPDATE OR DELETE ON permissions
+FOR EACH ROW EXECUTE FUNCTION notify_auth_change();
+
+CREATE TRIGGER groups_auth_change
+AFTER UPDATE OR DELETE ON groups
+FOR EACH ROW EXECUTE FUNCTION notify_auth_change();
+
+CREATE TRIGGER users_auth_change
+AFTER UPDATE OF group_id OR DELETE ON users
+FOR EACH ROW EXECUTE FUNCTION notify_auth_change();
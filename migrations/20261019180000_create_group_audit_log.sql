@@ -0,0 +1,23 @@
+CREATE TYPE AUDIT_ACTION AS ENUM ('create_group', 'edit_group', 'grant', 'revoke', 'move_user');
+
+CREATE TABLE group_audit_log (
+    id        INTEGER      GENERATED ALWAYS AS IDENTITY,
+    actor_id  UUID, /* NULL = deleted user */
+    acted_at  TIMESTAMPTZ  NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    action    AUDIT_ACTION NOT NULL,
+    group_id  INTEGER, /* NULL = Anonymous user's group */
+    /* Set for grant and revoke */
+    operation OPERATION,
+    resource  RESOURCE,
+    /* The user that was moved, for move_user */
+    user_id   UUID,
+    /* Human-readable description of what changed */
+    details   TEXT         NOT NULL DEFAULT '',
+
+    PRIMARY KEY (id),
+    FOREIGN KEY (actor_id) REFERENCES users ON DELETE SET NULL,
+    FOREIGN KEY (group_id) REFERENCES groups ON DELETE CASCADE,
+    FOREIGN KEY (user_id)  REFERENCES users ON DELETE SET NULL
+);
+
+CREATE INDEX group_audit_log_group_id ON group_audit_log (group_id);
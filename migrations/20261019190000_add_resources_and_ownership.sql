@@ -0,0 +1,11 @@
+ALTER TYPE RESOURCE ADD VALUE 'tags';
+ALTER TYPE RESOURCE ADD VALUE 'pools';
+ALTER TYPE RESOURCE ADD VALUE 'comments';
+ALTER TYPE RESOURCE ADD VALUE 'users';
+ALTER TYPE RESOURCE ADD VALUE 'groups';
+ALTER TYPE RESOURCE ADD VALUE 'votes';
+ALTER TYPE RESOURCE ADD VALUE 'favourites';
+ALTER TYPE RESOURCE ADD VALUE 'uploads';
+
+/* Only allows the operation on things the user owns, e.g. their own uploads */
+ALTER TABLE permissions ADD COLUMN own_only BOOLEAN NOT NULL DEFAULT false;
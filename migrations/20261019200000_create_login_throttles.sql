@@ -0,0 +1,13 @@
+CREATE TYPE THROTTLE_KIND AS ENUM ('ip', 'username');
+
+/* Failed sign-ins, by where they came from and who they were for. Rows are
+   kept while locked out, or until failures are old enough to be forgotten */
+CREATE TABLE login_throttles (
+    kind            THROTTLE_KIND NOT NULL,
+    key             TEXT          NOT NULL,
+    failures        INTEGER       NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMPTZ   NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_until    TIMESTAMPTZ,
+
+    PRIMARY KEY (kind, key)
+);
@@ -0,0 +1,5 @@
+/* Uploads outlive their uploader's account, and show as anonymous */
+ALTER TABLE posts
+    DROP CONSTRAINT posts_uploader_id_fkey,
+    ADD  CONSTRAINT posts_uploader_id_fkey
+        FOREIGN KEY (uploader_id) REFERENCES users ON DELETE SET NULL;
@@ -0,0 +1,23 @@
+/* Accounts registered while registration needs approval can't sign in until a
+   superuser approves them. Existing accounts are approved. */
+ALTER TABLE users ADD COLUMN approved BOOLEAN NOT NULL DEFAULT true;
+
+CREATE TABLE invites (
+    id         INTEGER     GENERATED ALWAYS AS IDENTITY,
+    code       TEXT        NOT NULL,
+    /* Accounts registered with the invite are put in this group */
+    group_id   INTEGER     NOT NULL,
+    created_by UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ NOT NULL,
+    /* NULL until the invite is used. used_by is also NULL if the account
+       was deleted since. */
+    used_at    TIMESTAMPTZ,
+    used_by    UUID,
+
+    PRIMARY KEY (id),
+    FOREIGN KEY (group_id)   REFERENCES groups ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users ON DELETE SET NULL,
+    FOREIGN KEY (used_by)    REFERENCES users ON DELETE SET NULL,
+    UNIQUE (code)
+);
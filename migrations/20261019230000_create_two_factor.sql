@@ -0,0 +1,36 @@
+/* Members must sign in with two-factor authentication, setting it up first if
+   they haven't */
+ALTER TABLE groups ADD COLUMN require_2fa BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE user_totp (
+    user_id   UUID    NOT NULL,
+    secret    BYTEA   NOT NULL,
+    /* false until the user proves their authenticator has the secret */
+    enabled   BOOLEAN NOT NULL DEFAULT false,
+    /* Time step of the last accepted code. Codes for it or earlier steps are
+       rejected, so they can't be replayed. */
+    last_step BIGINT  NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (user_id),
+    FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE
+);
+
+CREATE TABLE recovery_codes (
+    user_id UUID NOT NULL,
+    /* SHA-256 of the code, hex encoded */
+    hash    TEXT NOT NULL,
+
+    FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE,
+    UNIQUE (user_id, hash)
+);
+
+/* A sign-in waiting for its second factor */
+CREATE TABLE login_challenges (
+    token      UUID        NOT NULL DEFAULT uuid_generate_v4(),
+    user_id    UUID        NOT NULL,
+    remember   BOOLEAN     NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (token),
+    FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE
+);
@@ -0,0 +1,34 @@
+/* NULL for accounts created through OpenID Connect, which have no password
+   until they set one */
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+
+/* An account at an OpenID Connect provider that can sign in as a user */
+CREATE TABLE oidc_identities (
+    id        INTEGER     GENERATED ALWAYS AS IDENTITY,
+    issuer    TEXT        NOT NULL,
+    /* The provider's ID for the account, its `sub` claim */
+    subject   TEXT        NOT NULL,
+    user_id   UUID        NOT NULL,
+    linked_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (id),
+    FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE,
+    UNIQUE (issuer, subject)
+);
+
+CREATE INDEX oidc_identities_user_id ON oidc_identities (user_id);
+
+/* A sign-in that was sent to the provider and hasn't come back yet */
+CREATE TABLE oidc_logins (
+    state        TEXT        NOT NULL,
+    nonce        TEXT        NOT NULL,
+    /* PKCE code verifier */
+    verifier     TEXT        NOT NULL,
+    /* Set when linking an identity to this user rather than signing in */
+    link_user_id UUID,
+    remember     BOOLEAN     NOT NULL DEFAULT false,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (state),
+    FOREIGN KEY (link_user_id) REFERENCES users ON DELETE CASCADE
+);
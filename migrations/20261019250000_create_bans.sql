@@ -0,0 +1,24 @@
+CREATE TYPE RESTRICTION AS ENUM ('upload', 'tag', 'comment');
+
+/* Bans stay after they expire or are lifted, as the user's ban history */
+CREATE TABLE bans (
+    id           INTEGER     GENERATED ALWAYS AS IDENTITY,
+    user_id      UUID        NOT NULL,
+    /* NULL bans the user from the whole site */
+    restriction  RESTRICTION,
+    reason       TEXT        NOT NULL,
+    /* NULL once the moderator's account is deleted */
+    moderator_id UUID,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    /* NULL for bans that never expire */
+    expires_at   TIMESTAMPTZ,
+    lifted_at    TIMESTAMPTZ,
+    lifted_by    UUID,
+
+    PRIMARY KEY (id),
+    FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE,
+    FOREIGN KEY (moderator_id) REFERENCES users ON DELETE SET NULL,
+    FOREIGN KEY (lifted_by) REFERENCES users ON DELETE SET NULL
+);
+
+CREATE INDEX bans_user_id ON bans (user_id);
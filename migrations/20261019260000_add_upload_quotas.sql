@@ -0,0 +1,7 @@
+/* Most uploads each member may make in a rolling day, by count and by total
+   size. NULL for no limit. */
+ALTER TABLE groups
+    ADD COLUMN daily_upload_count INTEGER,
+    ADD COLUMN daily_upload_bytes BIGINT;
+
+CREATE INDEX posts_uploader_id_uploaded_at ON posts (uploader_id, uploaded_at);
@@ -0,0 +1,39 @@
+/* Posts are processing until their thumbnail is made, and failed if it
+   couldn't be. Either way they're shown with a placeholder. */
+CREATE TYPE POST_STATE AS ENUM ('processing', 'ready', 'failed');
+
+ALTER TABLE posts ADD COLUMN state POST_STATE NOT NULL DEFAULT 'ready';
+
+CREATE TYPE JOB_KIND AS ENUM ('thumbnail');
+
+/* Work for the server's background workers. Finished jobs are deleted. */
+CREATE TABLE jobs (
+    id         INTEGER     GENERATED ALWAYS AS IDENTITY,
+    kind       JOB_KIND    NOT NULL,
+    post_id    INTEGER     NOT NULL,
+    attempts   INTEGER     NOT NULL DEFAULT 0,
+    /* Not run before this. Pushed back while a worker has the job, and after
+       each failed attempt. */
+    run_at     TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_error TEXT,
+    /* Set once every attempt has failed, after which it only runs again if
+       retried by a superuser */
+    failed_at  TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (id),
+    FOREIGN KEY (post_id) REFERENCES posts ON DELETE CASCADE
+);
+
+CREATE INDEX jobs_run_at ON jobs (run_at) WHERE failed_at IS NULL;
+
+/* Where a post's thumbnail is served from */
+CREATE FUNCTION thumbnail_url(state POST_STATE, thumbnail_path TEXT)
+RETURNS TEXT
+LANGUAGE SQL IMMUTABLE
+AS $$
+    SELECT CASE state
+        WHEN 'ready' THEN '/static/thumb/' || thumbnail_path
+        ELSE '/static/placeholder.svg'
+    END;
+$$;
@@ -0,0 +1,33 @@
+/* Also tells running servers when sessions end and when users are banned or
+   unbanned, so other server processes don't keep signing them in from their
+   caches */
+CREATE OR REPLACE FUNCTION notify_auth_change() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_TABLE_NAME = 'permissions' THEN
+        IF TG_OP <> 'INSERT' THEN
+            PERFORM pg_notify('auth_changes', 'group:' || COALESCE(OLD.group_id::TEXT, ''));
+        END IF;
+        IF TG_OP <> 'DELETE' THEN
+            PERFORM pg_notify('auth_changes', 'group:' || COALESCE(NEW.group_id::TEXT, ''));
+        END IF;
+    ELSIF TG_TABLE_NAME = 'groups' THEN
+        PERFORM pg_notify('auth_changes', 'group:' || OLD.id);
+    ELSIF TG_TABLE_NAME = 'users' THEN
+        PERFORM pg_notify('auth_changes', 'user:' || OLD.id);
+    ELSIF TG_TABLE_NAME = 'sessions' THEN
+        PERFORM pg_notify('auth_changes', 'session:' || OLD.token);
+    ELSIF TG_TABLE_NAME = 'bans' THEN
+        PERFORM pg_notify('auth_changes', 'user:' || NEW.user_id);
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER sessions_auth_change
+AFTER DELETE ON sessions
+FOR EACH ROW EXECUTE FUNCTION notify_auth_change();
+
+CREATE TRIGGER bans_auth_change
+AFTER INSERT OR UPDATE OF lifted_at ON bans
+FOR EACH ROW EXECUTE FUNCTION notify_auth_change();
@@ -0,0 +1,14 @@
+/* Uploading is now checked against uploads rather than posts, and editing a
+   post's tags and pools against tags and pools. Groups keep what they could
+   already do. */
+INSERT INTO permissions (group_id, operation, resource, own_only)
+SELECT group_id, 'create', 'uploads', own_only
+FROM permissions
+WHERE operation = 'create' AND resource = 'posts'
+ON CONFLICT DO NOTHING;
+
+INSERT INTO permissions (group_id, operation, resource, own_only)
+SELECT permissions.group_id, 'update', split.resource, permissions.own_only
+FROM permissions, unnest(ARRAY['tags', 'pools']::RESOURCE[]) AS split (resource)
+WHERE permissions.operation = 'update' AND permissions.resource = 'posts'
+ON CONFLICT DO NOTHING;
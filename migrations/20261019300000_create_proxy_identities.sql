@@ -0,0 +1,13 @@
+/* A name an authenticating reverse proxy signs a user in as. It's kept apart
+   from their username, which they may change and anyone registering may take
+   before the proxy's user first turns up. */
+CREATE TABLE proxy_identities (
+    name      TEXT        NOT NULL,
+    user_id   UUID        NOT NULL,
+    linked_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (name),
+    FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE
+);
+
+CREATE INDEX proxy_identities_user_id ON proxy_identities (user_id);
@@ -0,0 +1,12 @@
+ALTER TABLE posts
+    ADD COLUMN parent_id INTEGER,
+    ADD CONSTRAINT parent_not_self CHECK (parent_id <> id),
+    ADD FOREIGN KEY (parent_id) REFERENCES posts ON DELETE SET NULL;
+
+ALTER TABLE post_versions
+    /* A post can gain or lose a parent, so unlike the other columns NULL can
+       be a change. These aren't foreign keys, so the history still shows
+       parents that have since been deleted. */
+    ADD COLUMN parent_changed BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN old_parent     INTEGER,
+    ADD COLUMN new_parent     INTEGER;
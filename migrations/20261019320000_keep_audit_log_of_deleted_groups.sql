@@ -0,0 +1,13 @@
+/* The group's name when the entry was made, so entries outlive the group.
+   group_id is NULL for both the anonymous user's group and deleted groups,
+   which only the latter have a name for. */
+ALTER TABLE group_audit_log ADD COLUMN group_name TEXT;
+
+UPDATE group_audit_log
+SET group_name = groups.name
+FROM groups
+WHERE groups.id = group_audit_log.group_id;
+
+ALTER TABLE group_audit_log
+    DROP CONSTRAINT group_audit_log_group_id_fkey,
+    ADD FOREIGN KEY (group_id) REFERENCES groups ON DELETE SET NULL;
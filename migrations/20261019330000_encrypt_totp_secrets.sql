@@ -0,0 +1,4 @@
+/* Whether secret is encrypted with accounts.totp-key, as a nonce followed by
+   the ciphertext. Secrets stored before the key was set stay in plaintext
+   until two-factor authentication is set up again. */
+ALTER TABLE user_totp ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,369 @@
+use askama_axum::IntoResponse;
+use axum::{extract::State, response::Redirect, routing::{get, post}, Form, Router};
+use axum_extra::extract::CookieJar;
+use uuid::Uuid;
+
+use crate::{
+    error::ResultExt,
+    extractors::{Authentication, Client, Settings},
+    throttle::Throttles,
+    tokens::session_user,
+};
+
+#[derive(askama_axum::Template)]
+#[template(path = "account.html")]
+struct AccountTemplate {
+    signed_in: bool,
+    settings: Settings,
+    username: String,
+    username_regex: regex::Regex,
+    password_regex: regex::Regex,
+    has_password: bool,
+    /// Whether the user can confirm changes with a code from their
+    /// authenticator, for accounts without a password
+    two_factor_enabled: bool,
+    oidc_enabled: bool,
+    identities: Vec<LinkedIdentity>,
+}
+
+#[derive(sqlx::FromRow)]
+struct LinkedIdentity {
+    id: i32,
+    issuer: String,
+    subject: String,
+    linked_at: time::OffsetDateTime,
+}
+
+#[derive(serde::Deserialize)]
+struct PasswordForm {
+    /// Left out by accounts without a password
+    #[serde(default)]
+    current_password: String,
+    /// Confirms the change instead for accounts without a password
+    #[serde(default)]
+    code: String,
+    new_password: String,
+}
+
+#[derive(serde::Deserialize)]
+struct UsernameForm {
+    username: String,
+}
+
+#[derive(serde::Deserialize)]
+struct DeleteForm {
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    code: String,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/auth/account", get(account_page))
+
+        .route("/api/auth/account/password", post(change_password))
+        .route("/api/auth/account/username", post(change_username))
+        .route("/api/auth/account/delete", post(delete_account))
+}
+
+async fn account_page(
+    auth: Authentication,
+    settings: Settings,
+    State(state): State<crate::State>,
+) -> crate::Result<impl IntoResponse> {
+    let user_id = session_user(&auth)?;
+
+    let (username, has_password): (String, bool) = sqlx::query_as("
+        SELECT username, password IS NOT NULL FROM users WHERE id = $1;
+    ")  .bind(user_id)
+        .fetch_one(&auth.db)
+        .await?;
+
+    let identities: Vec<LinkedIdentity> = sqlx::query_as("
+        SELECT id, issuer, subject, linked_at
+        FROM oidc_identities
+        WHERE user_id = $1
+        ORDER BY linked_at;
+    ")  .bind(user_id)
+        .fetch_all(&auth.db)
+        .await?;
+
+    Ok(AccountTemplate {
+        signed_in: true,
+        settings,
+        username,
+        username_regex: state.config.accounts.username_regex.clone(),
+        password_regex: state.config.accounts.password_regex.clone(),
+        has_password,
+        two_factor_enabled: crate::two_factor::enabled(&auth.db, user_id).await?,
+        oidc_enabled: state.config.oidc.is_some(),
+        identities,
+    })
+}
+
+pub async fn has_password(db: &sqlx::PgPool, user_id: Uuid) -> crate::Result<bool> {
+    Ok(sqlx::query_scalar("
+        SELECT password IS NOT NULL FROM users WHERE id = $1;
+    ")  .bind(user_id)
+        .fetch_one(db)
+        .await?)
+}
+
+/// How recently an account without a password or authenticator must have
+/// signed in through single sign-on for that to confirm a sensitive change
+const FRESH_SIGN_IN_MINUTES: i32 = 10;
+
+/// Checks the signed-in user's password before a sensitive change. Wrong
+/// guesses count against the same throttles as signing in, so a stolen session
+/// can't be used to guess the password. Accounts without one, made through
+/// OpenID Connect or a reverse proxy, confirm with a code from their
+/// authenticator instead, or failing that by having just signed in again.
+pub async fn confirm_password(
+    state: &crate::State,
+    client: &Client,
+    jar: &CookieJar,
+    user_id: Uuid,
+    password: String,
+    code: &str,
+) -> crate::Result<()> {
+    let (username, hash): (String, Option<String>) = sqlx::query_as("
+        SELECT username, password FROM users WHERE id = $1;
+    ")  .bind(user_id)
+        .fetch_one(&state.db)
+        .await?;
+
+    if let Some(hash) = hash {
+        let mut throttles = Throttles::new(client, &username);
+        throttles.reserve(&state.db, &state.config.accounts).await?;
+
+        if !state.hasher.verify(password, hash).await? {
+            return Err(crate::Error::BadRequest(String::from("Current password is incorrect")));
+        }
+
+        return throttles.release(&state.db).await;
+    }
+
+    if crate::two_factor::enabled(&state.db, user_id).await? {
+        let mut throttles = Throttles::new(client, &username);
+        throttles.reserve(&state.db, &state.config.accounts).await?;
+
+        if !crate::two_factor::check_code(state, user_id, code).await? {
+            return Err(crate::Error::BadRequest(String::from("Incorrect code")));
+        }
+
+        return throttles.release(&state.db).await;
+    }
+
+    // Requests signed in by a reverse proxy have no session, so they can't
+    // confirm this way
+    let session = jar.get("session").and_then(|c| Uuid::parse_str(c.value()).ok());
+    let fresh: Option<bool> = sqlx::query_scalar("
+        SELECT created_at > CURRENT_TIMESTAMP - make_interval(mins => $3)
+        FROM sessions
+        WHERE token = $1
+        AND user_id = $2;
+    ")  .bind(session)
+        .bind(user_id)
+        .bind(FRESH_SIGN_IN_MINUTES)
+        .fetch_optional(&state.db)
+        .await?;
+
+    match fresh {
+        Some(true) => Ok(()),
+        _ => Err(crate::Error::BadRequest(String::from("Log in again to confirm this"))),
+    }
+}
+
+/// Changes the password and ends every other session, keeping this one.
+async fn change_password(
+    auth: Authentication,
+    client: Client,
+    jar: CookieJar,
+    State(state): State<crate::State>,
+    Form(form): Form<PasswordForm>,
+) -> crate::Result<Redirect> {
+    let user_id = session_user(&auth)?;
+
+    if !state.config.accounts.password_regex.is_match(&form.new_password) {
+        return Err(crate::Error::BadRequest(String::from("Password does not meet requirements")));
+    }
+
+    confirm_password(&state, &client, &jar, user_id, form.current_password, &form.code).await?;
+
+    let password = state.hasher.hash(form.new_password).await?;
+    let current = jar.get("session").and_then(|c| Uuid::parse_str(c.value()).ok());
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query("
+        UPDATE users SET password = $2 WHERE id = $1;
+    ")  .bind(user_id)
+        .bind(password)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("
+        DELETE FROM sessions WHERE user_id = $1 AND token IS DISTINCT FROM $2;
+    ")  .bind(user_id)
+        .bind(current)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    state.auth_cache.forget_user(user_id);
+
+    Ok(Redirect::to("/auth/account"))
+}
+
+async fn change_username(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    Form(form): Form<UsernameForm>,
+) -> crate::Result<Redirect> {
+    let user_id = session_user(&auth)?;
+
+    if !state.config.accounts.username_regex.is_match(&form.username) {
+        return Err(crate::Error::BadRequest(String::from("Username does not meet requirements")));
+    }
+
+    sqlx::query("
+        UPDATE users SET username = $2 WHERE id = $1;
+    ")  .bind(user_id)
+        .bind(form.username)
+        .execute(&state.db)
+        .await
+        .on_constraint("username_unique", |_| crate::Error::Conflict(String::from("Username already taken")))?;
+
+    Ok(Redirect::to("/auth/account"))
+}
+
+/// Deletes the signed-in user. Their uploads stay up as anonymous posts, while
+/// votes, favourites, sessions, tokens and settings go with the account.
+async fn delete_account(
+    auth: Authentication,
+    client: Client,
+    jar: CookieJar,
+    State(state): State<crate::State>,
+    Form(form): Form<DeleteForm>,
+) -> crate::Result<(CookieJar, Redirect)> {
+    let user_id = session_user(&auth)?;
+
+    confirm_password(&state, &client, &jar, user_id, form.password, &form.code).await?;
+
+    let mut tx = state.db.begin().await?;
+    let superusers = crate::groups::lock_superusers(&mut tx).await?;
+    let superuser: bool = sqlx::query_scalar("
+        SELECT groups.superuser
+        FROM users
+        JOIN groups ON groups.id = users.group_id
+        WHERE users.id = $1
+        FOR UPDATE OF users;
+    ")  .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    if superuser && superusers.iter().all(|&id| id == user_id) {
+        return Err(crate::Error::BadRequest(String::from("Can't delete the last superuser")));
+    }
+
+    sqlx::query("
+        DELETE FROM users WHERE id = $1;
+    ")  .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    state.auth_cache.forget_user(user_id);
+
+    Ok((jar.remove(crate::auth::session_removal_cookie()), Redirect::to("/")))
+}
+
+impl LinkedIdentity {
+    fn linked_ago(&self) -> String {
+        timeago::Formatter::new().convert((time::OffsetDateTime::now_utc() - self.linked_at).unsigned_abs())
+    }
+
+    fn linked_at_rfc2822(&self) -> String {
+        self.linked_at
+            .format(&time::format_description::well_known::Rfc2822)
+            .expect("Couldn't convert identity timestamp to RFC2822 string")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use crate::testing::{app, post, session_count, session_token};
+
+    async fn user_count(db: &sqlx::PgPool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM users;").fetch_one(db).await.unwrap()
+    }
+
+    #[sqlx::test]
+    async fn changing_password_needs_current_one(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let token = session_token(&response);
+        post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(session_count(&db).await, 2);
+
+        let response = post(&app, "/api/auth/account/password", Some(&token), "current_password=wrong&new_password=swordfish").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=swordfish").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(session_count(&db).await, 2);
+
+        let response = post(&app, "/api/auth/account/password", Some(&token), "current_password=hunter2&new_password=swordfish").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        // Only the session that changed it is kept
+        assert_eq!(session_count(&db).await, 1);
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=swordfish").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn deleting_account_needs_password(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let token = session_token(&response);
+
+        let response = post(&app, "/api/auth/account/delete", Some(&token), "password=wrong").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(user_count(&db).await, 1);
+
+        let response = post(&app, "/api/auth/account/delete", Some(&token), "password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(user_count(&db).await, 0);
+        assert_eq!(session_count(&db).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn passwordless_accounts_need_fresh_sign_in(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let token = session_token(&response);
+        sqlx::query("UPDATE users SET password = NULL;").execute(&db).await.unwrap();
+        sqlx::query("UPDATE sessions SET created_at = CURRENT_TIMESTAMP - INTERVAL '1 hour';").execute(&db).await.unwrap();
+
+        let response = post(&app, "/api/auth/account/password", Some(&token), "new_password=swordfish").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = post(&app, "/api/auth/account/delete", Some(&token), "").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(user_count(&db).await, 1);
+
+        // An authenticator's code is asked for instead, when there is one
+        sqlx::query("
+            INSERT INTO user_totp (user_id, secret, enabled) SELECT id, $1, true FROM users;
+        ").bind(&b"12345678901234567890"[..]).execute(&db).await.unwrap();
+        sqlx::query("UPDATE sessions SET created_at = CURRENT_TIMESTAMP;").execute(&db).await.unwrap();
+        let response = post(&app, "/api/auth/account/delete", Some(&token), "code=000000").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(user_count(&db).await, 1);
+
+        sqlx::query("DELETE FROM user_totp;").execute(&db).await.unwrap();
+        let response = post(&app, "/api/auth/account/delete", Some(&token), "").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(user_count(&db).await, 0);
+    }
+}
@@ -1,42 +1,90 @@
-use argon2::{password_hash::{rand_core::OsRng, SaltString}, PasswordHasher, PasswordVerifier};
+use std::time::Duration;
 use askama_axum::IntoResponse;
-use axum::{extract::State, response::Redirect, routing::{get, post}, Form, Router};
+use axum::{extract::{self, State}, http::StatusCode, response::{Redirect, Response}, routing::{get, post}, Form, Router};
 use axum_extra::extract::{cookie::{Cookie, SameSite}, CookieJar};
 use sqlx::types::Uuid;
 
-use crate::{error::ResultExt, extractors::Authentication};
+use crate::{
+    bans,
+    config::Registration,
+    error::ResultExt,
+    extractors::{Authentication, Client, Settings},
+    oidc,
+    registration,
+    throttle::{self, Throttles},
+    two_factor,
+};
+
+/// How often expired sessions are deleted
+const SESSION_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 #[derive(askama_axum::Template)]
 #[template(path = "auth.html")]
 struct Auth {
     signed_in: bool,
+    settings: Settings,
     username_regex: regex::Regex,
     password_regex: regex::Regex,
+    registration: Registration,
+    /// Label of the OpenID Connect sign-in button, if configured
+    oidc_label: Option<String>,
+}
+
+#[derive(askama_axum::Template)]
+#[template(path = "sessions.html")]
+struct SessionsTemplate {
+    signed_in: bool,
+    settings: Settings,
+    sessions: Vec<Session>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Session {
+    pub id: i32,
+    pub created_at: time::OffsetDateTime,
+    pub last_seen_at: time::OffsetDateTime,
+    pub user_agent: String,
+    pub ip: String,
+    /* Additional information */
+    pub current: bool,
 }
 
 #[derive(serde::Deserialize)]
 struct Credentials {
     username: String,
     password: String,
+    /// Keep the session cookie after the browser closes
+    #[serde(default)]
+    remember: bool,
+    /// Invite code, only used when registering
+    #[serde(default)]
+    invite: String,
 }
 
 pub fn routes() -> Router<crate::State> {
     Router::new()
         .route("/auth", get(auth_page))
+        .route("/auth/sessions", get(sessions_page))
 
+        .route("/api/auth/sessions/:id/revoke", post(revoke_session))
         .route("/api/auth/logout", post(logout))
+        .route("/api/auth/logout/all", post(logout_everywhere))
         .route("/api/auth/login", post(login))
         .route("/api/auth/register", post(register))
 }
 
 async fn auth_page(
     auth: Authentication,
+    settings: Settings,
     State(state): State<crate::State>,
 ) -> impl IntoResponse {
     Auth {
         signed_in: auth.signed_in(),
+        settings,
         username_regex: state.config.accounts.username_regex.clone(),
         password_regex: state.config.accounts.password_regex.clone(),
+        registration: state.config.accounts.registration,
+        oidc_label: state.config.oidc.as_ref().map(|oidc| oidc.label.clone()),
     }
 }
 
@@ -48,45 +96,129 @@ async fn logout(
         _ = sqlx::query("
             DELETE FROM sessions WHERE token = $1;
         ").bind(session).execute(&state.db).await;
+        state.auth_cache.forget_session(&session);
 
-        // TODO: this doesn't remove the cookie for some reason
-        (jar.remove("session"), Redirect::to("/"))
+        (jar.remove(session_removal_cookie()), Redirect::to("/"))
     } else {
         (jar, Redirect::to("/"))
     }
 }
 
+/// Ends every session belonging to the signed-in user, including this one.
+async fn logout_everywhere(
+    auth: Authentication,
+    jar: CookieJar,
+    State(state): State<crate::State>,
+) -> crate::Result<(CookieJar, Redirect)> {
+    let Some(user_id) = auth.id else {
+        return Err(crate::Error::Unauthorized);
+    };
+
+    sqlx::query("
+        DELETE FROM sessions WHERE user_id = $1;
+    ")  .bind(user_id)
+        .execute(&auth.db)
+        .await?;
+    state.auth_cache.forget_user(user_id);
+
+    Ok((jar.remove(session_removal_cookie()), Redirect::to("/")))
+}
+
+/// The removal cookie must have the same path as the session cookie, or the
+/// browser treats it as a different cookie and keeps the session.
+pub fn session_removal_cookie() -> Cookie<'static> {
+    Cookie::build("session").path("/").build()
+}
+
 async fn login(
     jar: CookieJar,
+    client: Client,
     State(state): State<crate::State>,
     Form(desired): Form<Credentials>
-) -> crate::Result<CookieJar> {
-    let (user_id, correct_password): (Uuid, String) = sqlx::query_scalar("
-        SELECT (id, password)
+) -> crate::Result<Response> {
+    let mut throttles = Throttles::new(&client, &desired.username);
+    throttles.reserve(&state.db, &state.config.accounts).await?;
+
+    let user: Option<(Uuid, Option<String>, bool)> = sqlx::query_scalar("
+        SELECT (id, password, approved)
         FROM users
         WHERE username = $1; 
-    ")  .bind(desired.username)
-        .fetch_one(&state.db)
-        .await
-        .on_no_rows(crate::Error::Unauthorized)?;
+    ")  .bind(&desired.username)
+        .fetch_optional(&state.db)
+        .await?;
 
-    let password_ok = argon2::PasswordHash::new(&correct_password)
-        .map(|correct| argon2::Argon2::default().verify_password(desired.password.as_bytes(), &correct))
-        .flatten()
-        .is_ok();
+    let password_ok = match &user {
+        Some((_, Some(correct_password), _)) => state.hasher.verify(desired.password.clone(), correct_password.clone()).await?,
+        // Accounts created through OpenID Connect may have no password
+        _ => {
+            state.hasher.verify_dummy(desired.password.clone()).await?;
+            false
+        },
+    };
 
-    if password_ok {
-        add_sign_in_cookie(&state.db, user_id, jar).await
-    } else {
-        Err(crate::Error::Unauthorized)
+    match user {
+        Some((user_id, Some(old_hash), approved)) if password_ok => {
+            if !approved {
+                throttles.release(&state.db).await?;
+                return Err(crate::Error::BadRequest(String::from("Your account is awaiting approval")));
+            }
+
+            if state.hasher.needs_rehash(&old_hash) {
+                if let Err(e) = rehash(&state, user_id, &old_hash, desired.password).await {
+                    log::error!("Couldn't rehash password: {e}");
+                }
+            }
+
+            // Failed codes count against the same throttles, so failures are
+            // only forgiven once the code's right too
+            if two_factor::required(&state.db, user_id).await? {
+                throttles.release(&state.db).await?;
+                let jar = two_factor::start_challenge(&state, user_id, desired.remember, jar).await?;
+                return Ok((StatusCode::ACCEPTED, jar, "Two-factor authentication required").into_response());
+            }
+
+            throttles.succeed(&state.db).await?;
+            Ok(add_sign_in_cookie(&state, user_id, &client, desired.remember, jar).await?.into_response())
+        },
+        // Unknown usernames count too, so they can't be told apart. The
+        // reserved attempt is left counted as a failure.
+        _ => Err(crate::Error::Unauthorized),
     }
 }
 
+/// Replaces a password hash made with outdated Argon2 parameters, unless the
+/// password was changed in the meantime.
+async fn rehash(state: &crate::State, user_id: Uuid, old_hash: &str, password: String) -> crate::Result<()> {
+    let new_hash = state.hasher.hash(password).await?;
+    sqlx::query("
+        UPDATE users SET password = $3 WHERE id = $1 AND password = $2;
+    ")  .bind(user_id)
+        .bind(old_hash)
+        .bind(new_hash)
+        .execute(&state.db)
+        .await?;
+
+    Ok(())
+}
+
+/// Creates an account and signs in to it. Accounts that need approval are
+/// created without signing in, and the response is 202 Accepted instead.
 async fn register(
     jar: CookieJar,
+    client: Client,
     State(state): State<crate::State>,
     Form(desired): Form<Credentials>,
-) -> crate::Result<CookieJar> {
+) -> crate::Result<Response> {
+    let mode = state.config.accounts.registration;
+    let invite = Some(desired.invite.trim()).filter(|i| !i.is_empty());
+    match (mode, invite) {
+        (Registration::Closed, _) =>
+            return Err(crate::Error::BadRequest(String::from("Registration is closed"))),
+        (Registration::Invite, None) =>
+            return Err(crate::Error::BadRequest(String::from("An invite code is required to register"))),
+        _ => {},
+    }
+
     if !state.config.accounts.username_regex.is_match(&desired.username) {
         return Err(crate::Error::BadRequest(String::from("Username does not meet requirements")));
     }
@@ -95,37 +227,286 @@ async fn register(
         return Err(crate::Error::BadRequest(String::from("Password does not meet requirements")));
     }
 
-    let password = kdf(&desired.password);
+    let password = state.hasher.hash(desired.password).await?;
+
+    let mut tx = state.db.begin().await?;
+    let (invite_id, group_id) = match invite {
+        Some(code) => {
+            let (invite_id, group_id) = registration::claim_invite(&mut tx, code).await?;
+            (Some(invite_id), group_id)
+        },
+        None => (None, registration::default_group(&mut *tx, &state.config.accounts.default_group).await?),
+    };
+    let approved = invite_id.is_some() || mode != Registration::Approval;
+
     let id: Uuid = sqlx::query_scalar("
-        INSERT INTO users (username, password)
-        VALUES ($1, $2)
+        INSERT INTO users (group_id, username, password, approved)
+        VALUES ($1, $2, $3, $4)
         RETURNING id;
-    ")  .bind(desired.username)
+    ")  .bind(group_id)
+        .bind(desired.username)
         .bind(&password)
-        .fetch_one(&state.db)
+        .bind(approved)
+        .fetch_one(&mut *tx)
         .await
         .on_constraint("username_unique", |_| crate::Error::Conflict(String::from("Username already taken")))?;
 
-    add_sign_in_cookie(&state.db, id, jar).await
+    if let Some(invite_id) = invite_id {
+        registration::use_invite(&mut tx, invite_id, id).await?;
+    }
+    tx.commit().await?;
+
+    if !approved {
+        return Ok((StatusCode::ACCEPTED, "Your account is awaiting approval").into_response());
+    }
+
+    Ok(add_sign_in_cookie(&state, id, &client, desired.remember, jar).await?.into_response())
 }
 
-async fn add_sign_in_cookie(db: &sqlx::PgPool, user_id: Uuid, jar: CookieJar) -> crate::Result<CookieJar> {
+/// Starts a new session, replacing any session the client already had.
+/// Banned users can't sign in.
+pub async fn add_sign_in_cookie(
+    state: &crate::State,
+    user_id: Uuid,
+    client: &Client,
+    remember: bool,
+    jar: CookieJar,
+) -> crate::Result<CookieJar> {
+    if let Some(ban) = bans::active_ban(&state.db, user_id).await? {
+        return Err(crate::Error::Banned(ban));
+    }
+
+    if let Some(Ok(old_session)) = jar.get("session").map(|c| Uuid::parse_str(c.value())) {
+        sqlx::query("
+            DELETE FROM sessions WHERE token = $1;
+        ").bind(old_session).execute(&state.db).await?;
+        state.auth_cache.forget_session(&old_session);
+    }
+
     let token: Uuid = sqlx::query_scalar("
-        INSERT INTO sessions (user_id)
-        VALUES ($1)
+        INSERT INTO sessions (user_id, user_agent, ip)
+        VALUES ($1, $2, $3)
         RETURNING token;
-    ").bind(&user_id).fetch_one(db).await?;
+    ")  .bind(user_id)
+        .bind(&client.user_agent)
+        .bind(client.ip_string())
+        .fetch_one(&state.db)
+        .await?;
 
-    let cookie = Cookie::build(("session", token.as_hyphenated().to_string()))
+    let mut cookie = Cookie::build(("session", token.as_hyphenated().to_string()))
         .http_only(true)
         .same_site(SameSite::Strict)
-        .path("/")
-        .build();
+        .path("/");
+    // Otherwise the cookie lasts until the browser closes
+    if remember {
+        cookie = cookie.max_age(time::Duration::hours(state.config.accounts.session_max_hours.into()));
+    }
 
-    Ok(jar.add(cookie))
+    Ok(jar.add(cookie.build()))
 }
 
-pub fn kdf(input: &str) -> String {
-    let hasher = argon2::Argon2::default();
-    hasher.hash_password(input.as_bytes(), &SaltString::generate(&mut OsRng)).unwrap().to_string()
+async fn sessions_page(
+    auth: Authentication,
+    settings: Settings,
+    jar: CookieJar,
+) -> crate::Result<impl IntoResponse> {
+    let Some(user_id) = auth.id else {
+        return Err(crate::Error::Unauthorized);
+    };
+    let current = jar.get("session").and_then(|c| Uuid::parse_str(c.value()).ok());
+
+    let sessions: Vec<Session> = sqlx::query_as("
+        SELECT id, created_at, last_seen_at, user_agent, ip, token = $2 AS current
+        FROM sessions
+        WHERE user_id = $1
+        ORDER BY last_seen_at DESC;
+    ")  .bind(user_id)
+        .bind(current)
+        .fetch_all(&auth.db)
+        .await?;
+
+    Ok(SessionsTemplate {
+        signed_in: true,
+        settings,
+        sessions,
+    })
+}
+
+async fn revoke_session(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<i32>,
+) -> crate::Result<Redirect> {
+    let Some(user_id) = auth.id else {
+        return Err(crate::Error::Unauthorized);
+    };
+
+    let token: Uuid = sqlx::query_scalar("
+        DELETE FROM sessions WHERE id = $1 AND user_id = $2
+        RETURNING token;
+    ")  .bind(id)
+        .bind(user_id)
+        .fetch_one(&auth.db)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+    state.auth_cache.forget_session(&token);
+
+    Ok(Redirect::to("/auth/sessions"))
+}
+
+/// Deletes expired sessions forever. Expired sessions are already rejected by
+/// [`Authentication`], this just stops them, expired cache entries, stale
+/// login throttles, login challenges and abandoned OpenID Connect sign-ins
+/// piling up.
+pub async fn purge_sessions(state: crate::State) {
+    let mut interval = tokio::time::interval(SESSION_PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let purged = sqlx::query("
+            DELETE FROM sessions
+            WHERE created_at < CURRENT_TIMESTAMP - make_interval(hours => $1)
+            OR last_seen_at < CURRENT_TIMESTAMP - make_interval(hours => $2);
+        ")  .bind(state.config.accounts.session_max_hours as i32)
+            .bind(state.config.accounts.session_idle_hours as i32)
+            .execute(&state.db)
+            .await;
+
+        match purged {
+            Ok(purged) => log::debug!("Purged {} expired sessions", purged.rows_affected()),
+            Err(e) => log::error!("Couldn't purge expired sessions: {e}"),
+        }
+
+        match throttle::purge(&state.db, &state.config.accounts).await {
+            Ok(purged) => log::debug!("Purged {purged} stale login throttles"),
+            Err(e) => log::error!("Couldn't purge stale login throttles: {e}"),
+        }
+
+        match two_factor::purge_challenges(&state.db).await {
+            Ok(purged) => log::debug!("Purged {purged} expired login challenges"),
+            Err(e) => log::error!("Couldn't purge expired login challenges: {e}"),
+        }
+
+        match oidc::purge_logins(&state.db).await {
+            Ok(purged) => log::debug!("Purged {purged} abandoned OpenID Connect sign-ins"),
+            Err(e) => log::error!("Couldn't purge abandoned OpenID Connect sign-ins: {e}"),
+        }
+        state.auth_cache.purge_expired();
+    }
+}
+
+impl Session {
+    fn last_seen_ago(&self) -> String {
+        timeago::Formatter::new().convert((time::OffsetDateTime::now_utc() - self.last_seen_at).unsigned_abs())
+    }
+
+    fn created_at_rfc2822(&self) -> String {
+        self.created_at
+            .format(&time::format_description::well_known::Rfc2822)
+            .expect("Couldn't convert session timestamp to RFC2822 string")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use crate::testing::{app, post, session_cookie, session_count, session_token};
+
+    #[sqlx::test]
+    async fn register_signs_in(db: sqlx::PgPool) {
+        let app = app(db.clone());
+
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = session_cookie(&response).unwrap();
+        assert!(cookie.contains("HttpOnly"));
+        assert!(cookie.contains("Path=/"));
+        assert_eq!(session_count(&db).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn register_rejects_taken_username(db: sqlx::PgPool) {
+        let app = app(db);
+
+        post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=other").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[sqlx::test]
+    async fn register_rejects_invalid_username(db: sqlx::PgPool) {
+        let app = app(db);
+
+        let response = post(&app, "/api/auth/register", None, "username=!&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn login_checks_password(db: sqlx::PgPool) {
+        let app = app(db);
+        post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(session_cookie(&response).is_some());
+
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=wrong").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(session_cookie(&response).is_none());
+
+        let response = post(&app, "/api/auth/login", None, "username=bob&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn remember_sets_max_age(db: sqlx::PgPool) {
+        let app = app(db);
+
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2&remember=true").await;
+        assert!(session_cookie(&response).unwrap().contains("Max-Age="));
+
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert!(!session_cookie(&response).unwrap().contains("Max-Age="));
+    }
+
+    #[sqlx::test]
+    async fn logout_removes_cookie_and_session(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let token = session_token(&response);
+
+        let response = post(&app, "/api/auth/logout", Some(&token), "").await;
+        assert!(response.status().is_redirection());
+        // Without a matching path the browser would keep the old cookie
+        let removal = session_cookie(&response).expect("Logout didn't remove the session cookie");
+        assert!(removal.contains("Path=/"));
+        assert!(removal.contains("Max-Age=0"));
+        assert_eq!(session_count(&db).await, 0);
+
+        let response = post(&app, "/api/auth/logout/all", Some(&token), "").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn logout_everywhere_ends_all_sessions(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        post(&app, "/api/auth/register", None, "username=bob&password=hunter2").await;
+        let mut tokens = Vec::new();
+        for _ in 0..3 {
+            let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+            tokens.push(session_token(&response));
+        }
+        assert_eq!(session_count(&db).await, 5);
+
+        let response = post(&app, "/api/auth/logout/all", Some(&tokens[0]), "").await;
+        assert!(response.status().is_redirection());
+        assert!(session_cookie(&response).unwrap().contains("Path=/"));
+        // Only bob's session is left
+        assert_eq!(session_count(&db).await, 1);
+
+        let response = post(&app, "/api/auth/sessions/1/revoke", Some(&tokens[1]), "").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
 }
@@ -0,0 +1,317 @@
+//! Bans from the whole site, and restrictions from doing one thing on it. Both
+//! are imposed by moderators, who may update any user, for a while or for good.
+
+use axum::{extract::{self, State}, response::Redirect, routing::post, Form, Router};
+use uuid::Uuid;
+
+use crate::{
+    error::ResultExt,
+    extractors::{Authentication, Operation::*, Permission, Resource::*},
+    schema::all_variants,
+};
+
+/// Longest reason a moderator can give
+const MAX_REASON_LENGTH: usize = 1000;
+
+#[derive(Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[sqlx(type_name = "RESTRICTION", rename_all = "lowercase")]
+pub enum Restriction {
+    Upload,
+    Tag,
+    /// Nothing can be commented on yet, but the restriction can be given ahead
+    /// of time
+    Comment,
+}
+
+/// A ban in force, as explained to the banned user
+#[derive(Debug)]
+pub struct ActiveBan {
+    pub reason: String,
+    /// `None` if it never expires
+    pub expires_at: Option<time::OffsetDateTime>,
+}
+
+/// A ban in a user's history, as shown to moderators
+#[derive(sqlx::FromRow)]
+pub struct BanRecord {
+    pub id: i32,
+    pub restriction: Option<Restriction>,
+    pub reason: String,
+    pub created_at: time::OffsetDateTime,
+    pub expires_at: Option<time::OffsetDateTime>,
+    pub lifted_at: Option<time::OffsetDateTime>,
+    /* Additional information */
+    pub moderator: String,
+    pub lifted_by: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct BanForm {
+    /// Empty to ban from the whole site
+    restriction: String,
+    reason: String,
+    /// Empty to never expire
+    hours: String,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/api/users/:id/bans", post(ban))
+        .route("/api/bans/:id/lift", post(lift))
+}
+
+/// Moderators may update any user. API tokens can't moderate.
+pub async fn is_moderator(auth: &Authentication) -> crate::Result<bool> {
+    Ok(auth.token_id.is_none() && auth.has(Permission(Update, Users)).await?)
+}
+
+async fn require_moderator(auth: &Authentication) -> crate::Result<Uuid> {
+    match auth.id {
+        Some(id) if is_moderator(auth).await? => Ok(id),
+        _ => Err(crate::Error::Unauthorized),
+    }
+}
+
+/// Returns the ban keeping a user off the site, if any
+pub async fn active_ban(db: &sqlx::PgPool, user_id: Uuid) -> crate::Result<Option<ActiveBan>> {
+    let ban: Option<(String, Option<time::OffsetDateTime>)> = sqlx::query_as("
+        SELECT reason, expires_at
+        FROM bans
+        WHERE user_id = $1
+        AND restriction IS NULL
+        AND lifted_at IS NULL
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        ORDER BY expires_at DESC NULLS FIRST
+        LIMIT 1;
+    ")  .bind(user_id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(ban.map(|(reason, expires_at)| ActiveBan { reason, expires_at }))
+}
+
+/// Fails with [`crate::Error::Forbidden`] if the user is restricted from
+/// something
+pub async fn check_restriction(auth: &Authentication, restriction: Restriction) -> crate::Result<()> {
+    let Some(user_id) = auth.id else {
+        return Ok(());
+    };
+
+    let ban: Option<(String, Option<time::OffsetDateTime>)> = sqlx::query_as("
+        SELECT reason, expires_at
+        FROM bans
+        WHERE user_id = $1
+        AND restriction = $2
+        AND lifted_at IS NULL
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        ORDER BY expires_at DESC NULLS FIRST
+        LIMIT 1;
+    ")  .bind(user_id)
+        .bind(restriction)
+        .fetch_optional(&auth.db)
+        .await?;
+
+    match ban {
+        Some((reason, expires_at)) => Err(crate::Error::Forbidden(format!(
+            "You're restricted from {}{}: {reason}", restriction.gerund(), until(expires_at)))),
+        None => Ok(()),
+    }
+}
+
+/// Returns a user's bans, newest first
+pub async fn history(db: &sqlx::PgPool, user_id: Uuid) -> crate::Result<Vec<BanRecord>> {
+    Ok(sqlx::query_as("
+        SELECT
+            bans.id,
+            bans.restriction,
+            bans.reason,
+            bans.created_at,
+            bans.expires_at,
+            bans.lifted_at,
+
+            COALESCE(moderators.username, 'Deleted user') AS moderator,
+            lifters.username AS lifted_by
+        FROM bans
+        LEFT JOIN users AS moderators ON moderators.id = bans.moderator_id
+        LEFT JOIN users AS lifters ON lifters.id = bans.lifted_by
+        WHERE bans.user_id = $1
+        ORDER BY bans.created_at DESC;
+    ")  .bind(user_id)
+        .fetch_all(db)
+        .await?)
+}
+
+async fn ban(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    extract::Path(user_id): extract::Path<Uuid>,
+    Form(form): Form<BanForm>,
+) -> crate::Result<Redirect> {
+    let moderator_id = require_moderator(&auth).await?;
+
+    let restriction = match form.restriction.as_str() {
+        "" => None,
+        restriction => Some(*Restriction::ALL.iter()
+            .find(|r| r.to_string() == restriction)
+            .ok_or_else(|| crate::Error::BadRequest(format!("Invalid restriction '{restriction}'")))?),
+    };
+    let hours: Option<i32> = match form.hours.trim() {
+        "" => None,
+        hours => Some(hours.parse().ok().filter(|&h| h > 0)
+            .ok_or_else(|| crate::Error::BadRequest(String::from("Duration must be a positive number of hours")))?),
+    };
+    let reason = form.reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_LENGTH {
+        return Err(crate::Error::BadRequest(format!(
+            "Reason must be between 1 and {MAX_REASON_LENGTH} characters")));
+    }
+
+    if user_id == moderator_id {
+        return Err(crate::Error::BadRequest(String::from("You can't ban yourself")));
+    }
+    let superuser: bool = sqlx::query_scalar("
+        SELECT groups.superuser
+        FROM users
+        JOIN groups ON groups.id = users.group_id
+        WHERE users.id = $1;
+    ")  .bind(user_id)
+        .fetch_one(&state.db)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+    if superuser {
+        return Err(crate::Error::BadRequest(String::from("Superusers can't be banned")));
+    }
+
+    sqlx::query("
+        INSERT INTO bans (user_id, restriction, reason, moderator_id, expires_at)
+        VALUES ($1, $2, $3, $4, CURRENT_TIMESTAMP + make_interval(hours => $5));
+    ")  .bind(user_id)
+        .bind(restriction)
+        .bind(reason)
+        .bind(moderator_id)
+        .bind(hours)
+        .execute(&state.db)
+        .await?;
+    // Cached sessions would otherwise carry on until they expire
+    state.auth_cache.forget_user(user_id);
+
+    Ok(Redirect::to(&format!("/users/{user_id}")))
+}
+
+async fn lift(
+    auth: Authentication,
+    extract::Path(id): extract::Path<i32>,
+) -> crate::Result<Redirect> {
+    let moderator_id = require_moderator(&auth).await?;
+
+    let user_id: Uuid = sqlx::query_scalar("
+        UPDATE bans
+        SET lifted_at = CURRENT_TIMESTAMP, lifted_by = $2
+        WHERE id = $1
+        AND lifted_at IS NULL
+        RETURNING user_id;
+    ")  .bind(id)
+        .bind(moderator_id)
+        .fetch_one(&auth.db)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+
+    Ok(Redirect::to(&format!("/users/{user_id}")))
+}
+
+fn until(expires_at: Option<time::OffsetDateTime>) -> String {
+    expires_at.map(|at| format!(" until {}", rfc2822(at))).unwrap_or_default()
+}
+
+fn rfc2822(at: time::OffsetDateTime) -> String {
+    at.format(&time::format_description::well_known::Rfc2822)
+        .expect("Couldn't convert ban timestamp to RFC2822 string")
+}
+
+all_variants!(Restriction { Upload, Tag, Comment });
+
+impl Restriction {
+    pub fn gerund(&self) -> &'static str {
+        match self {
+            Restriction::Upload => "uploading",
+            Restriction::Tag => "tagging",
+            Restriction::Comment => "commenting",
+        }
+    }
+}
+
+impl std::fmt::Display for Restriction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Restriction::Upload => "upload",
+            Restriction::Tag => "tag",
+            Restriction::Comment => "comment",
+        })
+    }
+}
+
+impl ActiveBan {
+    pub fn expires(&self) -> Option<String> {
+        self.expires_at.map(rfc2822)
+    }
+}
+
+impl BanRecord {
+    pub fn active(&self) -> bool {
+        self.lifted_at.is_none()
+            && self.expires_at.is_none_or(|at| at > time::OffsetDateTime::now_utc())
+    }
+
+    /// What the ban is from
+    pub fn scope(&self) -> String {
+        match self.restriction {
+            Some(restriction) => format!("No {}", restriction.gerund()),
+            None => String::from("Banned"),
+        }
+    }
+
+    pub fn created_at_rfc2822(&self) -> String {
+        rfc2822(self.created_at)
+    }
+
+    pub fn expires(&self) -> String {
+        self.expires_at.map(rfc2822).unwrap_or_else(|| String::from("Never"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request, StatusCode}};
+    use tower::ServiceExt;
+
+    use crate::testing::{app, post, session_cookie, session_token};
+
+    #[sqlx::test]
+    async fn banned_users_are_turned_away(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let token = session_token(&response);
+        sqlx::query("
+            INSERT INTO bans (user_id, reason, expires_at) SELECT id, 'Spamming', CURRENT_TIMESTAMP + INTERVAL '1 day' FROM users;
+        ").execute(&db).await.unwrap();
+
+        let request = Request::get("/auth/account")
+            .header(header::COOKIE, format!("session={token}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Spamming"));
+
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(session_cookie(&response).is_none());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Spamming"));
+
+        sqlx::query("UPDATE bans SET lifted_at = CURRENT_TIMESTAMP;").execute(&db).await.unwrap();
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{collections::{HashMap, HashSet}, hash::Hash, sync::{Arc, RwLock}, time::{Duration, Instant}};
+use sqlx::postgres::PgListener;
+use uuid::Uuid;
+
+use crate::extractors::{Operation, Resource};
+
+/// Caches what [`crate::extractors::Authentication`] would otherwise query on
+/// every request. Entries expire after a TTL, and are invalidated as soon as
+/// [`listen`] hears that group membership, permissions or bans changed or that
+/// sessions ended. Anything changing them should still invalidate its own
+/// server's entries, which it does before the notification arrives.
+pub struct AuthCache {
+    /// Session token -> (user ID, group ID)
+    sessions: TtlMap<Uuid, (Uuid, i32)>,
+    /// Group ID -> what it may do. `None` is the anonymous group.
+    groups: TtlMap<Option<i32>, Arc<GroupPermissions>>,
+}
+
+pub struct GroupPermissions {
+    pub superuser: bool,
+    /// Operations allowed on anything
+    pub permissions: HashSet<(Operation, Resource)>,
+    /// Operations allowed only on things the user owns
+    pub own_permissions: HashSet<(Operation, Resource)>,
+}
+
+struct TtlMap<K, V> {
+    ttl: Duration,
+    entries: RwLock<HashMap<K, (Instant, V)>>,
+}
+
+impl AuthCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: TtlMap::new(ttl),
+            groups: TtlMap::new(ttl),
+        }
+    }
+
+    pub fn session(&self, token: &Uuid) -> Option<(Uuid, i32)> {
+        self.sessions.get(token)
+    }
+
+    pub fn insert_session(&self, token: Uuid, user: (Uuid, i32)) {
+        self.sessions.insert(token, user);
+    }
+
+    pub fn forget_session(&self, token: &Uuid) {
+        self.sessions.remove(token);
+    }
+
+    /// Forgets all of a user's sessions, e.g. when they sign out everywhere
+    /// or change group.
+    pub fn forget_user(&self, user_id: Uuid) {
+        self.sessions.retain(|_, &(id, _)| id != user_id);
+    }
+
+    pub fn group(&self, group_id: Option<i32>) -> Option<Arc<GroupPermissions>> {
+        self.groups.get(&group_id)
+    }
+
+    pub fn insert_group(&self, group_id: Option<i32>, permissions: GroupPermissions) -> Arc<GroupPermissions> {
+        let permissions = Arc::new(permissions);
+        self.groups.insert(group_id, Arc::clone(&permissions));
+        permissions
+    }
+
+    pub fn forget_group(&self, group_id: Option<i32>) {
+        self.groups.remove(&group_id);
+    }
+
+    pub fn clear(&self) {
+        self.sessions.retain(|_, _| false);
+        self.groups.retain(|_, _| false);
+    }
+
+    /// Frees the memory used by expired entries
+    pub fn purge_expired(&self) {
+        self.sessions.purge_expired();
+        self.groups.purge_expired();
+    }
+}
+
+/// Invalidates cached entries when the database notifies that permissions,
+/// groups, users, sessions or bans changed. See the `notify_auth_change` SQL function.
+pub async fn listen(state: crate::State) {
+    let mut listener = match PgListener::connect_with(&state.db).await {
+        Ok(listener) => listener,
+        Err(e) => return log::error!("Couldn't listen for authentication changes, relying on the cache TTL: {e}"),
+    };
+    if let Err(e) = listener.listen("auth_changes").await {
+        return log::error!("Couldn't listen for authentication changes, relying on the cache TTL: {e}");
+    }
+
+    loop {
+        match listener.try_recv().await {
+            Ok(Some(notification)) => match notification.payload().split_once(':') {
+                Some(("group", "")) => state.auth_cache.forget_group(None),
+                Some(("group", id)) => match id.parse() {
+                    Ok(id) => state.auth_cache.forget_group(Some(id)),
+                    Err(_) => log::warn!("Bad authentication change notification: {}", notification.payload()),
+                },
+                Some(("user", id)) => match id.parse() {
+                    Ok(id) => state.auth_cache.forget_user(id),
+                    Err(_) => log::warn!("Bad authentication change notification: {}", notification.payload()),
+                },
+                Some(("session", token)) => match token.parse() {
+                    Ok(token) => state.auth_cache.forget_session(&token),
+                    Err(_) => log::warn!("Bad authentication change notification: {}", notification.payload()),
+                },
+                _ => log::warn!("Bad authentication change notification: {}", notification.payload()),
+            },
+            // The connection was lost, so changes may have been missed
+            Ok(None) => {
+                log::warn!("Lost connection listening for authentication changes, clearing cache");
+                state.auth_cache.clear();
+            },
+            Err(e) => {
+                log::error!("Couldn't receive authentication changes: {e}");
+                state.auth_cache.clear();
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            },
+        }
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> TtlMap<K, V> {
+    fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: RwLock::default() }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.read().unwrap();
+        entries.get(key)
+            .filter(|(inserted, _)| inserted.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn insert(&self, key: K, value: V) {
+        self.entries.write().unwrap().insert(key, (Instant::now(), value));
+    }
+
+    fn remove(&self, key: &K) {
+        self.entries.write().unwrap().remove(key);
+    }
+
+    fn retain(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        self.entries.write().unwrap().retain(|k, (_, v)| f(k, v));
+    }
+
+    fn purge_expired(&self) {
+        self.entries.write().unwrap().retain(|_, (inserted, _)| inserted.elapsed() < self.ttl);
+    }
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -6,6 +6,14 @@ pub struct Config {
     pub network: Network,
     pub accounts: Accounts,
     pub data: Data,
+    /// Limits on uploads. Daily quotas are set per group.
+    #[serde(default)]
+    pub uploads: Uploads,
+    /// Signing in through an OpenID Connect provider, if configured
+    pub oidc: Option<Oidc>,
+    /// Trusting a header set by an authenticating reverse proxy, if configured
+    #[serde(rename = "proxy-auth")]
+    pub proxy_auth: Option<ProxyAuth>,
 }
 
 #[derive(Deserialize)]
@@ -17,6 +25,10 @@ pub struct Network {
 #[derive(Deserialize)]
 pub struct Data {
     media: std::path::PathBuf,
+    /// Where uploads are written while they stream in. Must be on the same
+    /// filesystem as `media`, so finished uploads can be moved rather than
+    /// copied. Defaults to a directory in `media`.
+    temp: Option<std::path::PathBuf>,
     pub thumbnails: DataThumbnails,
 }
 
@@ -25,6 +37,22 @@ pub struct DataThumbnails {
     pub resolution: u32,
 }
 
+#[derive(Deserialize)]
+pub struct Uploads {
+    /// Largest file that can be uploaded, in MiB
+    #[serde(rename = "max-file-mib", default = "default_max_file_mib")]
+    pub max_file_mib: u64,
+    #[serde(rename = "max-files-per-request", default = "default_max_files_per_request")]
+    pub max_files_per_request: usize,
+    /// Uploads decoded by FFmpeg at once, at most, and background job
+    /// workers. Defaults to the number of CPUs.
+    #[serde(rename = "processing-threads", default = "default_processing_threads")]
+    pub processing_threads: usize,
+    /// Longest FFmpeg may spend on one step of processing an upload
+    #[serde(rename = "processing-timeout-seconds", default = "default_processing_timeout_seconds")]
+    pub processing_timeout_seconds: u64,
+}
+
 #[derive(Deserialize)]
 pub struct Accounts {
     #[serde(with = "serde_regex", rename = "username-regex")]
@@ -33,6 +61,287 @@ pub struct Accounts {
     pub password_regex: regex::Regex,
     #[serde(rename = "initial-superuser-password")]
     pub initial_superuser_password: String,
+    #[serde(default)]
+    pub registration: Registration,
+    /// Name of the group accounts go in, unless they registered with an invite
+    #[serde(rename = "default-group", default = "default_default_group")]
+    pub default_group: String,
+    /// Hours a session may go unused before it expires
+    #[serde(rename = "session-idle-hours", default = "default_session_idle_hours")]
+    pub session_idle_hours: u32,
+    /// Hours after signing in that a session expires, however often it's used
+    #[serde(rename = "session-max-hours", default = "default_session_max_hours")]
+    pub session_max_hours: u32,
+    /// Seconds that sessions and group permissions are cached for
+    #[serde(rename = "auth-cache-seconds", default = "default_auth_cache_seconds")]
+    pub auth_cache_seconds: u64,
+    /// Failed sign-ins allowed per IP address or username before lockouts begin
+    #[serde(rename = "login-free-attempts", default = "default_login_free_attempts")]
+    pub login_free_attempts: u32,
+    /// Length of the first lockout, which doubles with each further failure
+    #[serde(rename = "login-lockout-seconds", default = "default_login_lockout_seconds")]
+    pub login_lockout_seconds: u64,
+    /// Longest lockout. Failures older than this are forgotten.
+    #[serde(rename = "login-lockout-max-seconds", default = "default_login_lockout_max_seconds")]
+    pub login_lockout_max_seconds: u64,
+    /// Argon2 memory cost. Changing any Argon2 parameter rehashes passwords as
+    /// users sign in.
+    #[serde(rename = "argon2-memory-kib", default = "default_argon2_memory_kib")]
+    pub argon2_memory_kib: u32,
+    #[serde(rename = "argon2-iterations", default = "default_argon2_iterations")]
+    pub argon2_iterations: u32,
+    #[serde(rename = "argon2-parallelism", default = "default_argon2_parallelism")]
+    pub argon2_parallelism: u32,
+    /// Passwords hashed at once, at most. Defaults to the number of CPUs.
+    #[serde(rename = "hashing-threads", default = "default_hashing_threads")]
+    pub hashing_threads: usize,
+    /// Key that two-factor authentication secrets are encrypted with. Without
+    /// one they're stored in plaintext, so anyone who can read the database
+    /// can generate users' codes.
+    #[serde(rename = "totp-key")]
+    pub totp_key: Option<SecretKey>,
+}
+
+/// A 256-bit key, written as 64 hex digits
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+pub struct SecretKey(pub [u8; 32]);
+
+/// Who can create an account
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Registration {
+    /// Anyone
+    #[default]
+    Open,
+    /// Nobody
+    Closed,
+    /// Only people with an invite code
+    Invite,
+    /// Anyone, but accounts can't sign in until a superuser approves them.
+    /// Invite codes skip approval.
+    Approval,
+}
+
+impl std::fmt::Display for Registration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Registration::Open => "open",
+            Registration::Closed => "closed",
+            Registration::Invite => "invite-only",
+            Registration::Approval => "by approval",
+        })
+    }
+}
+
+fn default_max_file_mib() -> u64 {
+    100
+}
+
+fn default_max_files_per_request() -> usize {
+    20
+}
+
+fn default_processing_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+fn default_processing_timeout_seconds() -> u64 {
+    60
+}
+
+fn default_default_group() -> String {
+    String::from("users")
+}
+
+fn default_session_idle_hours() -> u32 {
+    24 * 14
+}
+
+fn default_session_max_hours() -> u32 {
+    24 * 90
+}
+
+fn default_auth_cache_seconds() -> u64 {
+    60
+}
+
+fn default_login_free_attempts() -> u32 {
+    5
+}
+
+fn default_login_lockout_seconds() -> u64 {
+    30
+}
+
+fn default_login_lockout_max_seconds() -> u64 {
+    60 * 60
+}
+
+fn default_argon2_memory_kib() -> u32 {
+    argon2::Params::DEFAULT_M_COST
+}
+
+fn default_argon2_iterations() -> u32 {
+    argon2::Params::DEFAULT_T_COST
+}
+
+fn default_argon2_parallelism() -> u32 {
+    argon2::Params::DEFAULT_P_COST
+}
+
+fn default_hashing_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+#[derive(Deserialize)]
+pub struct Oidc {
+    /// The provider's discovery document is fetched from
+    /// `<issuer>/.well-known/openid-configuration`
+    pub issuer: String,
+    #[serde(rename = "client-id")]
+    pub client_id: String,
+    #[serde(rename = "client-secret")]
+    pub client_secret: String,
+    /// This server's `/auth/oidc/callback`, as registered with the provider
+    #[serde(rename = "redirect-uri")]
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Text of the sign-in button
+    #[serde(default = "default_oidc_label")]
+    pub label: String,
+    /// Create accounts for identities that aren't linked to one yet
+    #[serde(rename = "auto-provision", default)]
+    pub auto_provision: bool,
+    /// Group for created accounts that no group mapping matches. Defaults to
+    /// `accounts.default-group`.
+    #[serde(rename = "default-group")]
+    pub default_group: Option<String>,
+    /// ID token claim to name created accounts after
+    #[serde(rename = "username-claim", default = "default_oidc_username_claim")]
+    pub username_claim: String,
+    /// ID token claim listing the user's groups at the provider
+    #[serde(rename = "groups-claim")]
+    pub groups_claim: Option<String>,
+    /// Moves users into a group when their groups claim has a value, checked
+    /// each time they sign in. The first match wins.
+    #[serde(rename = "group-mapping", default)]
+    pub group_mapping: Vec<GroupMapping>,
+}
+
+#[derive(Deserialize)]
+pub struct GroupMapping {
+    /// A value of the groups claim
+    pub claim: String,
+    /// Name of a group here
+    pub group: String,
+}
+
+#[derive(Deserialize)]
+pub struct ProxyAuth {
+    /// Header holding the signed-in user's name at the proxy. Accounts
+    /// created for new names are given it as their username.
+    #[serde(default = "default_proxy_auth_header")]
+    pub header: String,
+    /// Only requests from these addresses may set the header. Anyone else's
+    /// header is ignored, or they could sign in as anybody.
+    #[serde(rename = "trusted-proxies")]
+    pub trusted_proxies: Vec<Cidr>,
+    /// Group for created accounts. Defaults to `accounts.default-group`.
+    #[serde(rename = "default-group")]
+    pub default_group: Option<String>,
+}
+
+/// An IP network in CIDR notation, like `10.0.0.0/8`. A bare address is a
+/// network of just that address.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec![String::from("openid"), String::from("profile")]
+}
+
+fn default_oidc_label() -> String {
+    String::from("Log in with single sign-on")
+}
+
+fn default_oidc_username_claim() -> String {
+    String::from("preferred_username")
+}
+
+fn default_proxy_auth_header() -> String {
+    String::from("X-Remote-User")
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as mapped IPv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for SecretKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        hex::decode(s.trim()).ok()
+            .and_then(|key| key.try_into().ok())
+            .map(SecretKey)
+            .ok_or_else(|| String::from("Key must be 64 hex digits"))
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid network '{s}'");
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s.as_str(), None),
+        };
+
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&p| p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Self {
+            max_file_mib: default_max_file_mib(),
+            max_files_per_request: default_max_files_per_request(),
+            processing_threads: default_processing_threads(),
+            processing_timeout_seconds: default_processing_timeout_seconds(),
+        }
+    }
+}
+
+impl Uploads {
+    pub fn max_file_bytes(&self) -> u64 {
+        self.max_file_mib * 1024 * 1024
+    }
 }
 
 impl Data {
@@ -45,4 +354,9 @@ impl Data {
     pub fn thumbnails(&self) -> PathBuf {
         self.media.join("thumb")
     }
+
+    /// Returns the path for uploads that haven't finished yet
+    pub fn temp(&self) -> PathBuf {
+        self.temp.clone().unwrap_or_else(|| self.media.join("temp"))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,148 @@
+//! Cross-site request forgery protection by double-submitted tokens. Every
+//! browser gets a random token in the `csrf` cookie, which scripts copy into
+//! forms and request headers. Another site can make a browser send the cookie,
+//! but can't read it to send the copy.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    body::Body,
+    extract::{FromRequest, Request},
+    http::{header, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Form,
+};
+use axum_extra::extract::{cookie::{Cookie, SameSite}, CookieJar};
+
+const COOKIE: &str = "csrf";
+/// Form field holding the copy, for plain form posts
+pub const FIELD: &str = "csrf_token";
+/// Header holding the copy, for scripts
+const HEADER: &str = "x-csrf-token";
+/// Largest form body read looking for the token
+const MAX_FORM_BYTES: usize = 1024 * 1024;
+
+#[derive(serde::Deserialize)]
+struct TokenForm {
+    #[serde(rename = "csrf_token")]
+    token: String,
+}
+
+/// Rejects mutating requests without the client's token, and gives clients
+/// without one a token. Requests with an API token are exempt, since browsers
+/// never add `Authorization` headers by themselves.
+pub async fn protect(jar: CookieJar, request: Request, next: Next) -> crate::Result<Response> {
+    let token = jar.get(COOKIE)
+        .map(|c| c.value().to_string())
+        .filter(|t| t.len() == 64 && t.bytes().all(|b| b.is_ascii_hexdigit()));
+
+    let safe = matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE);
+    let bearer = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|a| a.to_str().ok())
+        .is_some_and(|a| a.starts_with("Bearer "));
+
+    let request = if safe || bearer {
+        request
+    } else {
+        check(token.as_deref(), request).await?
+    };
+
+    let response = next.run(request).await;
+    if token.is_some() {
+        return Ok(response);
+    }
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    // Scripts have to read it, so it can't be HTTP-only
+    let cookie = Cookie::build((COOKIE, hex::encode(bytes)))
+        .same_site(SameSite::Strict)
+        .path("/");
+
+    Ok((jar.add(cookie.build()), response).into_response())
+}
+
+/// Checks the request carries a copy of the cookie's token, in its headers or
+/// form body. The body is given back in the returned request.
+async fn check(token: Option<&str>, request: Request) -> crate::Result<Request> {
+    let forbidden = || crate::Error::Forbidden(String::from(
+        "Missing or wrong CSRF token, reload the page and try again"));
+    let token = token.ok_or_else(forbidden)?;
+
+    if let Some(copy) = request.headers().get(HEADER) {
+        return match copy.to_str() {
+            Ok(copy) if matches(token, copy) => Ok(request),
+            _ => Err(forbidden()),
+        };
+    }
+
+    let is_form = request.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .is_some_and(|t| t.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Err(forbidden());
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_FORM_BYTES).await
+        .map_err(|_| crate::Error::ContentTooLarge(String::from("Form is too large")))?;
+
+    let form = Request::builder()
+        .method(Method::POST)
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(bytes.clone()))
+        .map_err(anyhow::Error::from)?;
+    match Form::<TokenForm>::from_request(form, &()).await {
+        Ok(Form(form)) if matches(token, &form.token) => Ok(Request::from_parts(parts, Body::from(bytes))),
+        _ => Err(forbidden()),
+    }
+}
+
+/// Compares in constant time, so the token can't be guessed a byte at a time
+fn matches(token: &str, copy: &str) -> bool {
+    token.len() == copy.len()
+        && token.bytes().zip(copy.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request, StatusCode}};
+    use tower::ServiceExt;
+
+    use crate::testing::{app, CSRF};
+
+    #[sqlx::test]
+    async fn posts_need_csrf_token(db: sqlx::PgPool) {
+        let app = app(db);
+        let register = |cookie: &str, body: &str| {
+            let request = Request::post("/api/auth/register")
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .header(header::COOKIE, cookie)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = register("", "username=alice&password=hunter2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = register(&format!("csrf={CSRF}"), "username=alice&password=hunter2&csrf_token=wrong").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = register(&format!("csrf={CSRF}"), &format!("username=alice&password=hunter2&csrf_token={CSRF}")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // API tokens are exempt, so this gets as far as checking the token
+        let request = Request::post("/api/auth/logout/all")
+            .header(header::AUTHORIZATION, "Bearer invalid")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Clients without a token are given one
+        let response = app.clone().oneshot(Request::get("/auth").body(Body::empty()).unwrap()).await.unwrap();
+        assert!(response.headers()[header::SET_COOKIE].to_str().unwrap().starts_with("csrf="));
+    }
+}
@@ -1,6 +1,8 @@
-use axum::{http::StatusCode, response::{IntoResponse, Response}};
+use axum::{http::{header, StatusCode}, response::{IntoResponse, Response}};
 use sqlx::error::DatabaseError;
 
+use crate::{bans::ActiveBan, extractors::Settings};
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(thiserror::Error, Debug)]
@@ -11,12 +13,20 @@ pub enum Error {
     Conflict(String),
     #[error("Unauthorized")]
     Unauthorized,
+    #[error("Forbidden")]
+    Forbidden(String),
     #[error("Bad Request")]
     BadRequest(String),
     #[error("Unsupported Media Type")]
     UnsupportedMediaType(String),
     #[error("Not Found")]
     NotFound,
+    /// The signed-in user is banned from the site
+    #[error("Banned")]
+    Banned(ActiveBan),
+    /// Seconds until the client may try again
+    #[error("Too Many Requests")]
+    TooManyRequests(u64),
 
     #[error("Bad query: {0}")]
     Query(String),
@@ -26,6 +36,14 @@ pub enum Error {
     Internal(#[from] anyhow::Error),
 }
 
+#[derive(askama_axum::Template)]
+#[template(path = "banned.html")]
+struct BannedTemplate {
+    signed_in: bool,
+    settings: Settings,
+    ban: ActiveBan,
+}
+
 pub trait ResultExt<T> {
     fn on_constraint(
         self,
@@ -48,12 +66,24 @@ impl IntoResponse for Error {
                 (StatusCode::CONFLICT, conflict),
             Error::Unauthorized =>
                 (StatusCode::UNAUTHORIZED, String::new()),
+            Error::Forbidden(s) =>
+                (StatusCode::FORBIDDEN, s),
             Error::BadRequest(s) =>
                 (StatusCode::BAD_REQUEST, s),
             Error::UnsupportedMediaType(ty) =>
                 (StatusCode::UNSUPPORTED_MEDIA_TYPE, ty),
             Error::NotFound =>
                 (StatusCode::NOT_FOUND, String::new()),
+            // The user's own settings can't be loaded without authenticating
+            Error::Banned(ban) => return (
+                StatusCode::FORBIDDEN,
+                BannedTemplate { signed_in: true, settings: Settings::default(), ban },
+            ).into_response(),
+            Error::TooManyRequests(retry_after) => return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                format!("Too many attempts, try again in {retry_after} seconds"),
+            ).into_response(),
 
             Error::Query(s) =>
                 (StatusCode::BAD_REQUEST, s),
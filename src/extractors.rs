@@ -1,36 +1,116 @@
-use axum::{async_trait, extract::{FromRef, FromRequestParts}, http::request::Parts};
+use std::{convert::Infallible, net::{IpAddr, SocketAddr}, sync::Arc};
+use axum::{async_trait, extract::{ConnectInfo, FromRef, FromRequestParts}, http::{header, request::Parts}};
 use axum_extra::extract::{cookie::Cookie, CookieJar};
 use uuid::Uuid;
 
-use crate::{error::Error, traits::TransposeValues};
+use crate::{
+    bans,
+    cache::{AuthCache, GroupPermissions},
+    config::ProxyAuth,
+    error::{Error, ResultExt},
+    posts::Rating,
+    schema::all_variants,
+    traits::TransposeValues,
+};
 
+pub(crate) const PAGE_SIZES: std::ops::RangeInclusive<i32> = 1..=200;
+pub(crate) const THUMBNAIL_SIZES: std::ops::RangeInclusive<i32> = 50..=1000;
+/// Most blacklist entries a user may have, as each one is checked by every
+/// listing
+pub(crate) const MAX_BLACKLIST_LENGTH: usize = 100;
+
+/// A user's display preferences. Stored in `user_settings` for signed-in users
+/// and in the `settings` cookie for anonymous users.
+#[derive(Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+#[serde(default)]
 pub struct Settings {
-    
+    pub page_size: i32,
+    pub max_rating: Rating,
+    /// Query expressions matching posts to hide
+    pub blacklist: Vec<String>,
+    /// Width and height bound for thumbnails in listings, in pixels
+    pub thumbnail_size: i32,
+    pub theme: Theme,
+    pub layout: Layout,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, sqlx::Type, serde::Serialize, serde::Deserialize)]
+#[sqlx(type_name = "THEME", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    #[default]
+    Light,
+    Dark,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, sqlx::Type, serde::Serialize, serde::Deserialize)]
+#[sqlx(type_name = "LAYOUT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Rows of varying height that fill the page width
+    #[default]
+    Justified,
+    Columns,
+    /// Needs `grid-template-rows: masonry`, which few browsers support
+    Masonry,
+    Grid,
+}
+
+/// Who's on the other end of a request.
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    pub user_agent: String,
 }
 
-/// Extract and validate a users session token, retrieving their user ID.
+/// Extract and validate a users session token or API token, retrieving their
+/// user ID. It's kept in the request's extensions once extracted, so later
+/// extractors needing it don't authenticate the request again.
+#[derive(Clone)]
 pub struct Authentication {
     pub db: sqlx::PgPool,
+    cache: Arc<AuthCache>,
     pub group_id: Option<i32>,
     pub id: Option<Uuid>,
+    /// Set when authenticated by an API token rather than a session, which
+    /// limits the user's permissions to the token's scopes.
+    pub token_id: Option<i32>,
 }
     
 pub struct Permission(pub Operation, pub Resource);
 
-#[derive(sqlx::Type)]
-#[sqlx(rename_all = "lowercase")]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, sqlx::Type)]
+#[sqlx(type_name = "OPERATION", rename_all = "lowercase")]
 pub enum Operation {
     Read,
-    Modify,
+    Update,
     Delete,
     Create,
 }
 
-#[derive(sqlx::Type)]
-#[sqlx(rename_all = "lowercase")]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, sqlx::Type)]
+#[sqlx(type_name = "RESOURCE", rename_all = "lowercase")]
+/// What "own" means for each resource, for permissions granted only on things
+/// the user owns, is up to the code checking it. Usually it's whoever created
+/// the thing.
 pub enum Resource {
+    /// Updating covers a post's source and rating
     Posts,
     Wiki,
+    /// Updating covers the tags on a post, which its uploader owns
+    Tags,
+    /// Updating covers the pools a post is in, which its uploader owns
+    Pools,
+    /// Nothing checks this until there's a way to comment
+    Comments,
+    /// A user owns their own account
+    Users,
+    Groups,
+    /// Nothing checks this until there's a way to vote
+    Votes,
+    /// Nothing checks this until there's a way to favourite posts
+    Favourites,
+    /// Creating covers uploading new posts
+    Uploads,
 }
 
 impl Authentication {
@@ -38,74 +118,567 @@ impl Authentication {
         self.id.is_some()
     }
 
+    /// Checks whether the user may perform an operation on any instance of a
+    /// resource.
     pub async fn has(&self, permission: Permission) -> crate::Result<bool> {
+        self.has_on(permission, None).await
+    }
+
+    /// Checks whether the user may perform an operation on something owned by
+    /// `owner`, which also passes if they may only do it to their own things.
+    pub async fn has_on(&self, permission: Permission, owner: Option<Uuid>) -> crate::Result<bool> {
+        let group = self.group().await?;
+        let key = (permission.0, permission.1);
+        let owns = self.id.is_some() && owner == self.id;
+
+        let allowed = group.superuser
+            || group.permissions.contains(&key)
+            || (owns && group.own_permissions.contains(&key));
+        if !allowed {
+            return Ok(false);
+        }
+
+        let Some(token_id) = self.token_id else {
+            return Ok(true);
+        };
+
         Ok(sqlx::query_scalar("
             SELECT EXISTS (
                 SELECT 1 AS result
-                FROM groups
-                WHERE id = $1
-                AND superuser = true
-            ) OR EXISTS (
-                SELECT 1 AS result
-                FROM permissions
-                WHERE group_id IS NOT DISTINCT FROM $1
+                FROM api_token_scopes
+                WHERE token_id = $1
                 AND operation = $2
                 AND resource = $3
             );
-        ")  .bind(self.group_id)
+        ")  .bind(token_id)
             .bind(permission.0)
             .bind(permission.1)
             .fetch_one(&self.db)
             .await?)
     }
-}
 
-#[async_trait]
-impl<S> FromRequestParts<S> for Authentication
-where
-    crate::State: FromRef<S>,
-    S: Send + Sync,
-{
-    type Rejection = Error;
+    /// Superusers can administer the site, which can't be done with an API
+    /// token.
+    pub async fn is_superuser(&self) -> crate::Result<bool> {
+        Ok(self.token_id.is_none() && self.group().await?.superuser)
+    }
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let state = crate::State::from_ref(state);
+    /// Returns what the user's group may do
+    async fn group(&self) -> crate::Result<Arc<GroupPermissions>> {
+        if let Some(group) = self.cache.group(self.group_id) {
+            return Ok(group);
+        }
+
+        let superuser: bool = sqlx::query_scalar("
+            SELECT EXISTS (
+                SELECT 1 AS result
+                FROM groups
+                WHERE id = $1
+                AND superuser = true
+            );
+        ")  .bind(self.group_id)
+            .fetch_one(&self.db)
+            .await?;
+
+        let permissions: Vec<(Operation, Resource, bool)> = sqlx::query_as("
+            SELECT operation, resource, own_only
+            FROM permissions
+            WHERE group_id IS NOT DISTINCT FROM $1;
+        ")  .bind(self.group_id)
+            .fetch_all(&self.db)
+            .await?;
+
+        let (own, any): (Vec<_>, Vec<_>) = permissions.into_iter().partition(|&(_, _, own_only)| own_only);
+        Ok(self.cache.insert_group(self.group_id, GroupPermissions {
+            superuser,
+            permissions: any.into_iter().map(|(o, r, _)| (o, r)).collect(),
+            own_permissions: own.into_iter().map(|(o, r, _)| (o, r)).collect(),
+        }))
+    }
+
+    /// Authenticates the request by an API token, a trusted proxy's header or
+    /// a session cookie, in that order.
+    async fn authenticate(parts: &mut Parts, state: crate::State) -> crate::Result<Self> {
         let jar = CookieJar::from_headers(&parts.headers);
+        let Ok(client) = Client::from_request_parts(parts, &state).await;
+
+        if let Some(authorization) = parts.headers.get(header::AUTHORIZATION) {
+            let token = authorization.to_str().ok()
+                .and_then(|a| a.strip_prefix("Bearer "))
+                .ok_or(Error::Unauthorized)?;
+
+            return Self::from_api_token(state, &client, token.trim()).await;
+        }
+
+        let config = Arc::clone(&state.config);
+        if let Some(proxy) = &config.proxy_auth {
+            if let Some(name) = proxy_name(proxy, &client, parts) {
+                return Self::from_proxy_header(state, proxy, name).await;
+            }
+        }
 
         let token = jar.get("session")
             .map(Cookie::value).map(Uuid::parse_str)
             .transpose().ok().flatten();
 
-        let (id, group_id): (Option<Uuid>, Option<i32>) = sqlx::query_scalar("
-            WITH user_id AS (
-                SELECT user_id AS id
+        let cached = token.and_then(|token| state.auth_cache.session(&token));
+        let user = match (token, cached) {
+            (_, Some(user)) => Some(user),
+            (None, None) => None,
+            (Some(token), None) => {
+                let user = Self::from_session(&state, &client, token).await?;
+                if let Some(user) = user {
+                    // Banned sessions aren't cached, so the ban is checked
+                    // again until it ends
+                    if let Some(ban) = bans::active_ban(&state.db, user.0).await? {
+                        return Err(Error::Banned(ban));
+                    }
+                    state.auth_cache.insert_session(token, user);
+                }
+
+                user
+            },
+        };
+        let (id, group_id) = user.transpose_values();
+
+        Ok(Self { db: state.db, cache: state.auth_cache, group_id, id, token_id: None })
+    }
+
+    /// Validates a session token, returning its user's ID and group ID.
+    async fn from_session(state: &crate::State, client: &Client, token: Uuid) -> crate::Result<Option<(Uuid, i32)>> {
+        // Sessions are renewed at most once a minute to save a write per
+        // request. Cached sessions aren't renewed at all, but the cache TTL is
+        // about as long.
+        Ok(sqlx::query_scalar("
+            WITH session AS (
+                SELECT token, user_id, last_seen_at
                 FROM sessions
                 WHERE token = $1
-            ), group_id AS (
-                SELECT group_id AS id
-                FROM users, user_id
-                WHERE users.id = user_id.id
+                AND created_at > CURRENT_TIMESTAMP - make_interval(hours => $2)
+                AND last_seen_at > CURRENT_TIMESTAMP - make_interval(hours => $3)
+            ), renewal AS (
+                UPDATE sessions
+                SET last_seen_at = CURRENT_TIMESTAMP, ip = $4, user_agent = $5
+                FROM session
+                WHERE sessions.token = session.token
+                AND session.last_seen_at < CURRENT_TIMESTAMP - INTERVAL '1 minute'
             )
-            SELECT (user_id.id, group_id.id)
-            FROM user_id, group_id
+            SELECT (users.id, users.group_id)
+            FROM session
+            JOIN users ON users.id = session.user_id
         ")  .bind(token)
+            .bind(state.config.accounts.session_max_hours as i32)
+            .bind(state.config.accounts.session_idle_hours as i32)
+            .bind(client.ip_string())
+            .bind(&client.user_agent)
             .fetch_optional(&state.db)
-            .await?
-            .transpose_values();
+            .await?)
+    }
+
+    /// Looks up the user behind an `Authorization: Bearer` API token. Unlike
+    /// sessions, a bad token is an error rather than signing the client out,
+    /// so scripts notice.
+    async fn from_api_token(state: crate::State, client: &Client, token: &str) -> crate::Result<Self> {
+        // Like sessions, last use is recorded at most once a minute
+        let (id, group_id, token_id): (Uuid, i32, i32) = sqlx::query_as("
+            WITH token AS (
+                SELECT id, user_id, last_used_at
+                FROM api_tokens
+                WHERE hash = $1
+            ), usage AS (
+                UPDATE api_tokens
+                SET last_used_at = CURRENT_TIMESTAMP, last_used_ip = $2
+                FROM token
+                WHERE api_tokens.id = token.id
+                AND (token.last_used_at IS NULL OR token.last_used_at < CURRENT_TIMESTAMP - INTERVAL '1 minute')
+            )
+            SELECT users.id, users.group_id, token.id
+            FROM token
+            JOIN users ON users.id = token.user_id;
+        ")  .bind(crate::tokens::hash(token))
+            .bind(client.ip_string())
+            .fetch_one(&state.db)
+            .await
+            .on_no_rows(Error::Unauthorized)?;
+
+        if let Some(ban) = bans::active_ban(&state.db, id).await? {
+            return Err(Error::Banned(ban));
+        }
+
+        Ok(Self {
+            db: state.db,
+            cache: state.auth_cache,
+            group_id: Some(group_id),
+            id: Some(id),
+            token_id: Some(token_id),
+        })
+    }
 
-        Ok(Self { db: state.db, group_id, id })
+    /// Looks up the user a trusted reverse proxy signed in, creating their
+    /// account on first sight. The proxy vouches for every request, so
+    /// there's no session. Users without an approved account are signed out.
+    async fn from_proxy_header(state: crate::State, proxy: &ProxyAuth, name: &str) -> crate::Result<Self> {
+        let user = match Self::find_proxy_user(&state.db, name).await? {
+            Some(user) => Some(user),
+            None => Self::create_proxy_user(&state, proxy, name).await?,
+        };
+
+        let (id, group_id) = match user {
+            Some((id, group_id, true)) => (Some(id), Some(group_id)),
+            _ => (None, None),
+        };
+        if let Some(id) = id {
+            if let Some(ban) = bans::active_ban(&state.db, id).await? {
+                return Err(Error::Banned(ban));
+            }
+        }
+
+        Ok(Self { db: state.db, cache: state.auth_cache, group_id, id, token_id: None })
+    }
+
+    /// Returns the ID, group ID and whether they're approved of the user a
+    /// proxy name is linked to. Names aren't matched against usernames, which
+    /// users may change and anyone registering may take.
+    async fn find_proxy_user(db: &sqlx::PgPool, name: &str) -> crate::Result<Option<(Uuid, i32, bool)>> {
+        Ok(sqlx::query_as("
+            SELECT users.id, users.group_id, users.approved
+            FROM proxy_identities
+            JOIN users ON users.id = proxy_identities.user_id
+            WHERE proxy_identities.name = $1;
+        ")  .bind(name)
+            .fetch_optional(db)
+            .await?)
+    }
+
+    /// Creates an account named after a proxy name seen for the first time,
+    /// and links the name to it. A name taken by a local account is left
+    /// without one, rather than signing in to somebody else's.
+    async fn create_proxy_user(state: &crate::State, proxy: &ProxyAuth, name: &str) -> crate::Result<Option<(Uuid, i32, bool)>> {
+        if !state.config.accounts.username_regex.is_match(name) {
+            log::warn!("Reverse proxy user '{name}' doesn't meet username requirements");
+            return Ok(None);
+        }
+
+        let group = proxy.default_group.as_ref().unwrap_or(&state.config.accounts.default_group);
+        let group_id = crate::registration::default_group(&state.db, group).await?;
+
+        let mut tx = state.db.begin().await?;
+        // Concurrent first requests race to create the account, and the losers
+        // wait here until the winner's linked it
+        let created: Option<Uuid> = sqlx::query_scalar("
+            INSERT INTO users (group_id, username)
+            VALUES ($1, $2)
+            ON CONFLICT (username) DO NOTHING
+            RETURNING id;
+        ")  .bind(group_id)
+            .bind(name)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        let Some(user_id) = created else {
+            tx.rollback().await?;
+            let user = Self::find_proxy_user(&state.db, name).await?;
+            if user.is_none() {
+                log::warn!("Reverse proxy user '{name}' has no account, as a local account has that username");
+            }
+
+            return Ok(user);
+        };
+
+        sqlx::query("
+            INSERT INTO proxy_identities (name, user_id)
+            VALUES ($1, $2);
+        ")  .bind(name)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        log::info!("Created account '{name}' for reverse proxy user");
+
+        Self::find_proxy_user(&state.db, name).await
+    }
+}
+
+/// Returns who a trusted reverse proxy says the client is
+fn proxy_name<'a>(proxy: &ProxyAuth, client: &Client, parts: &'a Parts) -> Option<&'a str> {
+    let name = parts.headers.get(proxy.header.as_str())?.to_str().ok()?;
+    let ip = client.ip?;
+    if !proxy.trusted_proxies.iter().any(|network| network.contains(ip)) {
+        log::debug!("Ignored {} header from untrusted address {ip}", proxy.header);
+        return None;
+    }
+
+    Some(name).filter(|n| !n.is_empty())
+}
+
+all_variants!(Theme { Light, Dark });
+
+all_variants!(Layout { Justified, Columns, Masonry, Grid });
+
+all_variants!(Operation { Read, Create, Update, Delete });
+
+all_variants!(Resource {
+    Posts,
+    Uploads,
+    Tags,
+    Pools,
+    Comments,
+    Votes,
+    Favourites,
+    Wiki,
+    Users,
+    Groups,
+});
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Operation::Read => "read",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+            Operation::Create => "create",
+        })
+    }
+}
+
+impl std::fmt::Display for Resource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Resource::Posts => "posts",
+            Resource::Wiki => "wiki",
+            Resource::Tags => "tags",
+            Resource::Pools => "pools",
+            Resource::Comments => "comments",
+            Resource::Users => "users",
+            Resource::Groups => "groups",
+            Resource::Votes => "votes",
+            Resource::Favourites => "favourites",
+            Resource::Uploads => "uploads",
+        })
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.0, self.1)
+    }
+}
+
+impl std::str::FromStr for Permission {
+    type Err = Error;
+
+    /// Parses the `operation:resource` form written by [`Permission`]'s `Display`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::BadRequest(format!("Invalid permission '{s}'"));
+        let (operation, resource) = s.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Permission(
+            *Operation::ALL.iter().find(|o| o.to_string() == operation).ok_or_else(invalid)?,
+            *Resource::ALL.iter().find(|r| r.to_string() == resource).ok_or_else(invalid)?,
+        ))
     }
 }
 
 #[async_trait]
-impl<S> FromRequestParts<S> for Settings
+impl<S> FromRequestParts<S> for Authentication
+where
+    crate::State: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(auth) = parts.extensions.get::<Self>() {
+            return Ok(auth.clone());
+        }
+
+        let auth = Self::authenticate(parts, crate::State::from_ref(state)).await?;
+        parts.extensions.insert(auth.clone());
+
+        Ok(auth)
+    }
+}
+
+impl Client {
+    pub fn ip_string(&self) -> String {
+        self.ip.map(|ip| ip.to_string()).unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Client
 where S: Send + Sync
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ip = parts.extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let user_agent = parts.headers
+            .get(header::USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        Ok(Self { ip, user_agent })
+    }
+}
+
+impl Settings {
+    /// Brings settings from the `settings` cookie, which the client may have
+    /// written anything to, within the limits [`crate::settings`] enforces
+    /// when saving them.
+    fn clamped(mut self) -> Self {
+        self.page_size = self.page_size.clamp(*PAGE_SIZES.start(), *PAGE_SIZES.end());
+        self.thumbnail_size = self.thumbnail_size.clamp(*THUMBNAIL_SIZES.start(), *THUMBNAIL_SIZES.end());
+        self.blacklist.truncate(MAX_BLACKLIST_LENGTH);
+        self
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            page_size: 50,
+            max_rating: Rating::Explicit,
+            blacklist: Vec::new(),
+            thumbnail_size: 320,
+            theme: Theme::default(),
+            layout: Layout::default(),
+        }
+    }
+}
+
+impl std::fmt::Display for Theme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        })
+    }
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Layout::Justified => "justified",
+            Layout::Columns => "columns",
+            Layout::Masonry => "masonry",
+            Layout::Grid => "grid",
+        })
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Settings
+where
+    crate::State: FromRef<S>,
+    S: Send + Sync,
 {
     type Rejection = Error;
 
+    /// Loads the signed-in user's settings, or the anonymous user's settings
+    /// cookie. Missing or unreadable settings fall back to the defaults.
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let auth = Authentication::from_request_parts(parts, state).await?;
+
+        if let Some(user_id) = auth.id {
+            let settings: Option<Settings> = sqlx::query_as("
+                SELECT page_size, max_rating, blacklist, thumbnail_size, theme, layout
+                FROM user_settings
+                WHERE user_id = $1;
+            ")  .bind(user_id)
+                .fetch_optional(&auth.db)
+                .await?;
+
+            return Ok(settings.unwrap_or_default());
+        }
+
         let jar = CookieJar::from_headers(&parts.headers);
 
-        Ok(Self { })
+        Ok(jar.get("settings")
+            .and_then(|c| serde_json::from_str(c.value()).ok())
+            .map(Settings::clamped)
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use axum::{body::Body, extract::ConnectInfo, http::{Request, StatusCode}};
+    use tower::ServiceExt;
+
+    use super::{Settings, MAX_BLACKLIST_LENGTH, PAGE_SIZES, THUMBNAIL_SIZES};
+    use crate::testing::{app_with, post, session_count};
+
+    #[test]
+    fn settings_cookies_are_clamped() {
+        let blacklist = vec!["\"x\""; MAX_BLACKLIST_LENGTH + 1].join(",");
+        let cookie = format!(r#"{{"page_size":-5,"thumbnail_size":100000,"blacklist":[{blacklist}]}}"#);
+        let settings = serde_json::from_str::<Settings>(&cookie).unwrap().clamped();
+
+        assert_eq!(settings.page_size, *PAGE_SIZES.start());
+        assert_eq!(settings.thumbnail_size, *THUMBNAIL_SIZES.end());
+        assert_eq!(settings.blacklist.len(), MAX_BLACKLIST_LENGTH);
+    }
+
+    #[sqlx::test]
+    async fn proxy_header_only_trusted_from_proxies(db: sqlx::PgPool) {
+        let app = app_with(db.clone(), r#"
+            [proxy-auth]
+            trusted-proxies = ["10.0.0.0/8"]
+        "#);
+        let account_page = |ip: &str| {
+            let mut request = Request::get("/auth/account")
+                .header("X-Remote-User", "alice")
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip.parse().unwrap(), 40000)));
+            app.clone().oneshot(request)
+        };
+        let user_count = || sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users;").fetch_one(&db);
+
+        let response = account_page("192.0.2.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(user_count().await.unwrap(), 0);
+
+        let response = account_page("10.1.2.3").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = account_page("::ffff:10.1.2.3").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(user_count().await.unwrap(), 1);
+        assert_eq!(session_count(&db).await, 0);
+    }
+    #[sqlx::test]
+    async fn proxy_names_arent_usernames(db: sqlx::PgPool) {
+        let app = app_with(db.clone(), r#"
+            [proxy-auth]
+            trusted-proxies = ["10.0.0.0/8"]
+        "#);
+        let account_page = |name: &str| {
+            let mut request = Request::get("/auth/account")
+                .header("X-Remote-User", name)
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::new([10, 0, 0, 1].into(), 40000)));
+            app.clone().oneshot(request)
+        };
+
+        // Registering a proxy user's name first doesn't get their account
+        post(&app, "/api/auth/register", None, "username=mallory&password=hunter2").await;
+        let response = account_page("mallory").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Nor does renaming to it
+        let response = account_page("alice").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        sqlx::query("UPDATE users SET username = 'alice2' WHERE username = 'alice';").execute(&db).await.unwrap();
+        sqlx::query("UPDATE users SET username = 'alice' WHERE username = 'mallory';").execute(&db).await.unwrap();
+        let response = account_page("alice").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains(r#"value="alice2""#));
     }
 }
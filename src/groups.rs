@@ -0,0 +1,779 @@
+use std::collections::HashMap;
+use askama_axum::IntoResponse;
+use axum::{extract::{self, State}, response::Redirect, routing::{get, post}, Form, Router};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    error::ResultExt,
+    extractors::{Authentication, Operation, Permission, Resource, Settings},
+    schema::all_variants,
+    two_factor,
+};
+
+/// How many audit log entries to show
+const AUDIT_LIMIT: i64 = 100;
+/// Longest allowed group name, in characters
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(askama_axum::Template)]
+#[template(path = "groups.html")]
+struct GroupsTemplate {
+    signed_in: bool,
+    settings: Settings,
+    groups: Vec<GroupSummary>,
+    anonymous_permissions: Vec<String>,
+    audit: Vec<AuditEntry>,
+}
+
+#[derive(askama_axum::Template)]
+#[template(path = "group.html")]
+struct GroupTemplate {
+    signed_in: bool,
+    settings: Settings,
+    /// `None` for the anonymous group
+    group: Option<Group>,
+    /// Each resource with whether the group may perform each operation on it
+    permissions: Vec<(Resource, Vec<PermissionCell>)>,
+    members: Vec<Member>,
+    audit: Vec<AuditEntry>,
+}
+
+#[derive(sqlx::FromRow)]
+struct GroupSummary {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+    pub colour: String,
+    pub superuser: bool,
+    pub require_2fa: bool,
+    /* Additional information */
+    pub member_count: i64,
+    pub permissions: Vec<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Group {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+    pub colour: String,
+    pub superuser: bool,
+    pub require_2fa: bool,
+    /// `None` if there's no limit
+    pub daily_upload_count: Option<i32>,
+    /// `None` if there's no limit
+    pub daily_upload_bytes: Option<i64>,
+}
+
+struct PermissionCell {
+    pub permission: Permission,
+    /// `None` if not granted, otherwise whether it's only granted on things
+    /// the user owns
+    pub own_only: Option<bool>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Member {
+    pub id: Uuid,
+    pub username: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct AuditEntry {
+    pub acted_at: time::OffsetDateTime,
+    pub action: AuditAction,
+    pub actor_id: Option<Uuid>,
+    pub actor: Option<String>,
+    pub group_id: Option<i32>,
+    pub group_name: Option<String>,
+    pub permission: Option<String>,
+    pub user_id: Option<Uuid>,
+    pub username: Option<String>,
+    pub details: String,
+}
+
+#[derive(Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "AUDIT_ACTION", rename_all = "snake_case")]
+pub enum AuditAction {
+    CreateGroup,
+    EditGroup,
+    Grant,
+    Revoke,
+    MoveUser,
+}
+
+#[derive(serde::Deserialize)]
+struct GroupForm {
+    name: String,
+    description: String,
+    colour: String,
+    /// Require two-factor authentication
+    #[serde(default)]
+    require_2fa: bool,
+    /// Empty for no limit
+    #[serde(default)]
+    daily_upload_count: String,
+    /// Empty for no limit
+    #[serde(default)]
+    daily_upload_mib: String,
+}
+
+#[derive(serde::Deserialize)]
+struct MoveForm {
+    username: String,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/groups", get(groups_page))
+        .route("/groups/anonymous", get(anonymous_page))
+        .route("/groups/:id", get(group_page))
+
+        .route("/api/groups", post(create_group))
+        .route("/api/groups/:id/edit", post(edit_group))
+        .route("/api/groups/anonymous/permissions", post(set_anonymous_permissions))
+        .route("/api/groups/:id/permissions", post(set_group_permissions))
+        .route("/api/groups/:id/members", post(move_member))
+}
+
+/// Returns the signed-in superuser's ID
+pub async fn require_superuser(auth: &Authentication) -> crate::Result<Uuid> {
+    match auth.id {
+        Some(id) if auth.is_superuser().await? => Ok(id),
+        _ => Err(crate::Error::Unauthorized),
+    }
+}
+
+/// Locks every superuser's account, returning their IDs. Anything that could
+/// leave no superusers takes these locks first, so two superusers can't each
+/// see the other is left and both go at once.
+pub async fn lock_superusers(conn: &mut PgConnection) -> crate::Result<Vec<Uuid>> {
+    Ok(sqlx::query_scalar("
+        SELECT users.id
+        FROM users
+        JOIN groups ON groups.id = users.group_id
+        WHERE groups.superuser = true
+        ORDER BY users.id
+        FOR UPDATE OF users;
+    ")  .fetch_all(conn)
+        .await?)
+}
+
+/// Trims and validates a submitted group, returning (name, description, colour)
+fn validate(form: &GroupForm) -> crate::Result<(&str, &str, &str)> {
+    let name = form.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(crate::Error::BadRequest(format!(
+            "Group name must be between 1 and {MAX_NAME_LENGTH} characters")));
+    }
+
+    // Colours end up in style attributes, so only allow what <input type="color"> sends
+    let colour = form.colour.trim();
+    let valid_colour = colour.len() == 7
+        && colour.starts_with('#')
+        && colour[1..].chars().all(|c| c.is_ascii_hexdigit());
+    if !valid_colour {
+        return Err(crate::Error::BadRequest(String::from("Colour must look like #rrggbb")));
+    }
+
+    Ok((name, form.description.trim(), colour))
+}
+
+/// Parses the daily upload quota, by count and by bytes
+fn validate_quota(form: &GroupForm) -> crate::Result<(Option<i32>, Option<i64>)> {
+    let count = match form.daily_upload_count.trim() {
+        "" => None,
+        count => Some(count.parse().ok().filter(|&c: &i32| c >= 0)
+            .ok_or_else(|| crate::Error::BadRequest(String::from("Daily upload count must be a whole number")))?),
+    };
+    let bytes = match form.daily_upload_mib.trim() {
+        "" => None,
+        mib => Some(mib.parse().ok().filter(|&m: &i64| m >= 0)
+            .and_then(|m| m.checked_mul(1024 * 1024))
+            .ok_or_else(|| crate::Error::BadRequest(String::from("Daily upload size must be a whole number of MiB")))?),
+    };
+
+    Ok((count, bytes))
+}
+
+fn describe_limit<T: std::fmt::Display>(limit: Option<T>) -> String {
+    limit.map(|l| l.to_string()).unwrap_or_else(|| String::from("none"))
+}
+
+async fn audit(
+    conn: &mut PgConnection,
+    actor_id: Uuid,
+    action: AuditAction,
+    group_id: Option<i32>,
+    permission: Option<&Permission>,
+    user_id: Option<Uuid>,
+    details: &str,
+) -> crate::Result<()> {
+    sqlx::query("
+        INSERT INTO group_audit_log (actor_id, action, group_id, group_name, operation, resource, user_id, details)
+        VALUES ($1, $2, $3, (SELECT name FROM groups WHERE id = $3), $4, $5, $6, $7);
+    ")  .bind(actor_id)
+        .bind(action)
+        .bind(group_id)
+        .bind(permission.map(|p| p.0))
+        .bind(permission.map(|p| p.1))
+        .bind(user_id)
+        .bind(details)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the latest audit log entries, for every group if `group` is `None`
+async fn audit_log(db: &sqlx::PgPool, group: Option<Option<i32>>) -> crate::Result<Vec<AuditEntry>> {
+    Ok(sqlx::query_as("
+        SELECT
+            group_audit_log.acted_at,
+            group_audit_log.action,
+            group_audit_log.actor_id,
+            actor.username AS actor,
+            group_audit_log.group_id,
+            COALESCE(groups.name, group_audit_log.group_name) AS group_name,
+            group_audit_log.operation::TEXT || ':' || group_audit_log.resource::TEXT AS permission,
+            group_audit_log.user_id,
+            subject.username AS username,
+            group_audit_log.details
+        FROM group_audit_log
+        LEFT JOIN users actor ON actor.id = group_audit_log.actor_id
+        LEFT JOIN users subject ON subject.id = group_audit_log.user_id
+        LEFT JOIN groups ON groups.id = group_audit_log.group_id
+        WHERE $1 OR (
+            group_audit_log.group_id IS NOT DISTINCT FROM $2
+            /* Deleted groups aren't the anonymous user's */
+            AND (group_audit_log.group_id IS NOT NULL OR group_audit_log.group_name IS NULL)
+        )
+        ORDER BY group_audit_log.id DESC
+        LIMIT $3;
+    ")  .bind(group.is_none())
+        .bind(group.flatten())
+        .bind(AUDIT_LIMIT)
+        .fetch_all(db)
+        .await?)
+}
+
+async fn groups_page(
+    auth: Authentication,
+    settings: Settings,
+) -> crate::Result<impl IntoResponse> {
+    require_superuser(&auth).await?;
+
+    let groups: Vec<GroupSummary> = sqlx::query_as("
+        SELECT id, name, description, colour, superuser, require_2fa,
+            (SELECT COUNT(*) FROM users WHERE group_id = groups.id) AS member_count,
+            ARRAY(
+                SELECT operation::TEXT || ':' || resource::TEXT || CASE WHEN own_only THEN ' (own)' ELSE '' END
+                FROM permissions
+                WHERE group_id = groups.id
+                ORDER BY resource, operation
+            ) AS permissions
+        FROM groups
+        ORDER BY superuser DESC, name;
+    ")  .fetch_all(&auth.db)
+        .await?;
+
+    let anonymous_permissions: Vec<String> = sqlx::query_scalar("
+        SELECT operation::TEXT || ':' || resource::TEXT || CASE WHEN own_only THEN ' (own)' ELSE '' END
+        FROM permissions
+        WHERE group_id IS NULL
+        ORDER BY resource, operation;
+    ")  .fetch_all(&auth.db)
+        .await?;
+
+    Ok(GroupsTemplate {
+        signed_in: true,
+        settings,
+        groups,
+        anonymous_permissions,
+        audit: audit_log(&auth.db, None).await?,
+    })
+}
+
+async fn anonymous_page(
+    auth: Authentication,
+    settings: Settings,
+) -> crate::Result<impl IntoResponse> {
+    render_group(auth, settings, None).await
+}
+
+async fn group_page(
+    auth: Authentication,
+    settings: Settings,
+    extract::Path(id): extract::Path<i32>,
+) -> crate::Result<impl IntoResponse> {
+    render_group(auth, settings, Some(id)).await
+}
+
+async fn render_group(
+    auth: Authentication,
+    settings: Settings,
+    group_id: Option<i32>,
+) -> crate::Result<GroupTemplate> {
+    require_superuser(&auth).await?;
+
+    let group: Option<Group> = match group_id {
+        Some(id) => Some(sqlx::query_as("
+            SELECT id, name, description, colour, superuser, require_2fa,
+                daily_upload_count, daily_upload_bytes
+            FROM groups
+            WHERE id = $1;
+        ")  .bind(id)
+            .fetch_one(&auth.db)
+            .await
+            .on_no_rows(crate::Error::NotFound)?),
+        None => None,
+    };
+
+    let granted = granted_permissions(&auth.db, group_id).await?;
+
+    let members: Vec<Member> = sqlx::query_as("
+        SELECT id, username
+        FROM users
+        WHERE group_id = $1
+        ORDER BY username;
+    ")  .bind(group_id)
+        .fetch_all(&auth.db)
+        .await?;
+
+    Ok(GroupTemplate {
+        signed_in: true,
+        settings,
+        group,
+        permissions: Resource::ALL.iter()
+            .map(|&r| (r, Operation::ALL.iter()
+                .map(|&o| PermissionCell {
+                    permission: Permission(o, r),
+                    own_only: granted.get(&(o, r)).copied(),
+                })
+                .collect()))
+            .collect(),
+        members,
+        audit: audit_log(&auth.db, Some(group_id)).await?,
+    })
+}
+
+async fn create_group(
+    auth: Authentication,
+    Form(form): Form<GroupForm>,
+) -> crate::Result<Redirect> {
+    let actor_id = require_superuser(&auth).await?;
+    let (name, description, colour) = validate(&form)?;
+    let (daily_upload_count, daily_upload_bytes) = validate_quota(&form)?;
+
+    let mut tx = auth.db.begin().await?;
+    let id: i32 = sqlx::query_scalar("
+        INSERT INTO groups (name, description, colour, require_2fa, daily_upload_count, daily_upload_bytes)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id;
+    ")  .bind(name)
+        .bind(description)
+        .bind(colour)
+        .bind(form.require_2fa)
+        .bind(daily_upload_count)
+        .bind(daily_upload_bytes)
+        .fetch_one(&mut *tx)
+        .await
+        .on_constraint("groups_name_key", |_| crate::Error::Conflict(String::from("Group name already taken")))?;
+
+    audit(&mut tx, actor_id, AuditAction::CreateGroup, Some(id), None, None, name).await?;
+    tx.commit().await?;
+
+    Ok(Redirect::to(&format!("/groups/{id}")))
+}
+
+async fn edit_group(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<i32>,
+    Form(form): Form<GroupForm>,
+) -> crate::Result<Redirect> {
+    let actor_id = require_superuser(&auth).await?;
+    let (name, description, colour) = validate(&form)?;
+    let (daily_upload_count, daily_upload_bytes) = validate_quota(&form)?;
+
+    let mut tx = auth.db.begin().await?;
+    let old: Group = sqlx::query_as("
+        SELECT id, name, description, colour, superuser, require_2fa,
+            daily_upload_count, daily_upload_bytes
+        FROM groups
+        WHERE id = $1
+        FOR UPDATE;
+    ")  .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+
+    let mut changes = Vec::new();
+    if old.name != name {
+        changes.push(format!("name: {} → {name}", old.name));
+    }
+    if old.description != description {
+        changes.push(format!("description: {} → {description}", old.description));
+    }
+    if old.colour != colour {
+        changes.push(format!("colour: {} → {colour}", old.colour));
+    }
+    if old.require_2fa != form.require_2fa {
+        changes.push(format!("require 2FA: {} → {}", old.require_2fa, form.require_2fa));
+    }
+    if old.daily_upload_count != daily_upload_count {
+        changes.push(format!("daily upload count: {} → {}",
+            describe_limit(old.daily_upload_count), describe_limit(daily_upload_count)));
+    }
+    if old.daily_upload_bytes != daily_upload_bytes {
+        changes.push(format!("daily upload bytes: {} → {}",
+            describe_limit(old.daily_upload_bytes), describe_limit(daily_upload_bytes)));
+    }
+
+    let mut signed_out = Vec::new();
+    if !changes.is_empty() {
+        sqlx::query("
+            UPDATE groups
+            SET name = $2, description = $3, colour = $4, require_2fa = $5,
+                daily_upload_count = $6, daily_upload_bytes = $7
+            WHERE id = $1;
+        ")  .bind(id)
+            .bind(name)
+            .bind(description)
+            .bind(colour)
+            .bind(form.require_2fa)
+            .bind(daily_upload_count)
+            .bind(daily_upload_bytes)
+            .execute(&mut *tx)
+            .await
+            .on_constraint("groups_name_key", |_| crate::Error::Conflict(String::from("Group name already taken")))?;
+
+        if form.require_2fa {
+            signed_out = two_factor::end_unprotected_sessions(&mut tx, id).await?;
+        }
+
+        audit(&mut tx, actor_id, AuditAction::EditGroup, Some(id), None, None, &changes.join("; ")).await?;
+    }
+    tx.commit().await?;
+    for user_id in signed_out {
+        state.auth_cache.forget_user(user_id);
+    }
+
+    Ok(Redirect::to(&format!("/groups/{id}")))
+}
+
+async fn set_anonymous_permissions(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> crate::Result<Redirect> {
+    set_permissions(auth, state, None, fields).await?;
+
+    Ok(Redirect::to("/groups/anonymous"))
+}
+
+async fn set_group_permissions(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<i32>,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> crate::Result<Redirect> {
+    set_permissions(auth, state, Some(id), fields).await?;
+
+    Ok(Redirect::to(&format!("/groups/{id}")))
+}
+
+/// Returns a group's permissions and whether each is only granted on things
+/// the user owns
+async fn granted_permissions(
+    conn: impl sqlx::PgExecutor<'_>,
+    group_id: Option<i32>,
+) -> crate::Result<HashMap<(Operation, Resource), bool>> {
+    let granted: Vec<(Operation, Resource, bool)> = sqlx::query_as("
+        SELECT operation, resource, own_only
+        FROM permissions
+        WHERE group_id IS NOT DISTINCT FROM $1;
+    ")  .bind(group_id)
+        .fetch_all(conn)
+        .await?;
+
+    Ok(granted.into_iter().map(|(o, r, own_only)| ((o, r), own_only)).collect())
+}
+
+/// Replaces a group's permissions with the submitted form, which maps each
+/// `operation:resource` to "any", "own" or nothing. Grants and revokes the
+/// difference.
+async fn set_permissions(
+    auth: Authentication,
+    state: crate::State,
+    group_id: Option<i32>,
+    fields: Vec<(String, String)>,
+) -> crate::Result<()> {
+    let actor_id = require_superuser(&auth).await?;
+
+    let mut desired = HashMap::new();
+    for (permission, grant) in fields.iter().filter(|(field, _)| field != crate::csrf::FIELD) {
+        let Permission(operation, resource) = permission.parse()?;
+        let own_only = match grant.as_str() {
+            "" => continue,
+            "own" => true,
+            "any" => false,
+            _ => return Err(crate::Error::BadRequest(format!("Invalid grant '{grant}'"))),
+        };
+        desired.insert((operation, resource), own_only);
+    }
+
+    let mut tx = auth.db.begin().await?;
+    if let Some(id) = group_id {
+        sqlx::query("
+            SELECT 1 FROM groups WHERE id = $1 FOR UPDATE;
+        ")  .bind(id)
+            .fetch_one(&mut *tx)
+            .await
+            .on_no_rows(crate::Error::NotFound)?;
+    }
+
+    let current = granted_permissions(&mut *tx, group_id).await?;
+
+    for (&(operation, resource), &own_only) in &desired {
+        if current.get(&(operation, resource)) == Some(&own_only) {
+            continue;
+        }
+
+        sqlx::query("
+            INSERT INTO permissions (group_id, operation, resource, own_only)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (group_id, operation, resource) DO UPDATE SET own_only = EXCLUDED.own_only;
+        ")  .bind(group_id)
+            .bind(operation)
+            .bind(resource)
+            .bind(own_only)
+            .execute(&mut *tx)
+            .await?;
+
+        let details = if own_only { "own only" } else { "" };
+        audit(&mut tx, actor_id, AuditAction::Grant, group_id, Some(&Permission(operation, resource)), None, details).await?;
+    }
+
+    for &(operation, resource) in current.keys().filter(|k| !desired.contains_key(k)) {
+        sqlx::query("
+            DELETE FROM permissions
+            WHERE group_id IS NOT DISTINCT FROM $1
+            AND operation = $2
+            AND resource = $3;
+        ")  .bind(group_id)
+            .bind(operation)
+            .bind(resource)
+            .execute(&mut *tx)
+            .await?;
+
+        audit(&mut tx, actor_id, AuditAction::Revoke, group_id, Some(&Permission(operation, resource)), None, "").await?;
+    }
+    tx.commit().await?;
+    state.auth_cache.forget_group(group_id);
+
+    Ok(())
+}
+
+/// Moves a user, by username, into a group
+async fn move_member(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<i32>,
+    Form(form): Form<MoveForm>,
+) -> crate::Result<Redirect> {
+    let actor_id = require_superuser(&auth).await?;
+
+    let mut tx = auth.db.begin().await?;
+    let target: Group = sqlx::query_as("
+        SELECT id, name, description, colour, superuser, require_2fa,
+            daily_upload_count, daily_upload_bytes
+        FROM groups
+        WHERE id = $1
+        FOR SHARE;
+    ")  .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+    let superusers = lock_superusers(&mut tx).await?;
+
+    let (user_id, old_group, old_group_name, was_superuser): (Uuid, i32, String, bool) = sqlx::query_as("
+        SELECT users.id, groups.id, groups.name, groups.superuser
+        FROM users
+        JOIN groups ON groups.id = users.group_id
+        WHERE users.username = $1
+        FOR UPDATE OF users;
+    ")  .bind(form.username.trim())
+        .fetch_one(&mut *tx)
+        .await
+        .on_no_rows(crate::Error::BadRequest(String::from("No user has that username")))?;
+
+    if old_group == id {
+        return Ok(Redirect::to(&format!("/groups/{id}")));
+    }
+
+    if was_superuser && !target.superuser && superusers.iter().all(|&id| id == user_id) {
+        return Err(crate::Error::BadRequest(String::from("Can't remove the last superuser")));
+    }
+
+    sqlx::query("
+        UPDATE users SET group_id = $2 WHERE id = $1;
+    ")  .bind(user_id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    if target.require_2fa {
+        two_factor::end_unprotected_sessions(&mut tx, id).await?;
+    }
+
+    audit(&mut tx, actor_id, AuditAction::MoveUser, Some(id), None, Some(user_id), &format!("from {old_group_name}")).await?;
+    tx.commit().await?;
+    state.auth_cache.forget_user(user_id);
+
+    Ok(Redirect::to(&format!("/groups/{id}")))
+}
+
+all_variants!(AuditAction { CreateGroup, EditGroup, Grant, Revoke, MoveUser });
+
+impl std::fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AuditAction::CreateGroup => "create_group",
+            AuditAction::EditGroup => "edit_group",
+            AuditAction::Grant => "grant",
+            AuditAction::Revoke => "revoke",
+            AuditAction::MoveUser => "move_user",
+        })
+    }
+}
+
+impl Group {
+    /// The daily upload size quota as the form takes it
+    pub fn daily_upload_mib(&self) -> String {
+        self.daily_upload_bytes.map(|b| (b / (1024 * 1024)).to_string()).unwrap_or_default()
+    }
+}
+
+impl PermissionCell {
+    /// The form value for this cell's current grant
+    fn grant(&self) -> &'static str {
+        match self.own_only {
+            None => "",
+            Some(true) => "own",
+            Some(false) => "any",
+        }
+    }
+}
+
+impl AuditEntry {
+    fn acted_ago(&self) -> String {
+        timeago::Formatter::new().convert((time::OffsetDateTime::now_utc() - self.acted_at).unsigned_abs())
+    }
+
+    fn acted_at_rfc2822(&self) -> String {
+        self.acted_at
+            .format(&time::format_description::well_known::Rfc2822)
+            .expect("Couldn't convert audit timestamp to RFC2822 string")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request, StatusCode}};
+    use tower::ServiceExt;
+
+    use crate::testing::{app, post, session_token};
+
+    /// Registers users, making the first a superuser, and returns their
+    /// sessions and the superuser group's ID
+    async fn setup(app: &axum::Router, db: &sqlx::PgPool, usernames: &[&str]) -> (Vec<String>, i32) {
+        let mut sessions = Vec::new();
+        for username in usernames {
+            let response = post(app, "/api/auth/register", None, &format!("username={username}&password=hunter2")).await;
+            sessions.push(session_token(&response));
+        }
+        let admins: i32 = sqlx::query_scalar("
+            INSERT INTO groups (name, superuser) VALUES ('admins', true) RETURNING id;
+        ").fetch_one(db).await.unwrap();
+        sqlx::query("
+            UPDATE users SET group_id = $1 WHERE username = $2;
+        ").bind(admins).bind(usernames[0]).execute(db).await.unwrap();
+
+        (sessions, admins)
+    }
+
+    async fn group_of(db: &sqlx::PgPool, username: &str) -> String {
+        sqlx::query_scalar("
+            SELECT groups.name FROM users JOIN groups ON groups.id = users.group_id WHERE username = $1;
+        ").bind(username).fetch_one(db).await.unwrap()
+    }
+
+    #[sqlx::test]
+    async fn last_superuser_is_kept(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let (sessions, admins) = setup(&app, &db, &["alice", "bob"]).await;
+        let users: i32 = sqlx::query_scalar("SELECT id FROM groups WHERE name = 'users';").fetch_one(&db).await.unwrap();
+
+        let response = post(&app, &format!("/api/groups/{users}/members"), Some(&sessions[0]), "username=alice").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(group_of(&db, "alice").await, "admins");
+        // Only superusers manage groups
+        let response = post(&app, &format!("/api/groups/{admins}/members"), Some(&sessions[1]), "username=bob").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = post(&app, &format!("/api/groups/{admins}/members"), Some(&sessions[0]), "username=bob").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(group_of(&db, "bob").await, "admins");
+
+        // Each tries to demote the other at once, and only one can
+        let demotions: Vec<_> = [(0, "bob"), (1, "alice")].into_iter().map(|(actor, username)| {
+            let (app, session) = (app.clone(), sessions[actor].clone());
+            tokio::spawn(async move {
+                post(&app, &format!("/api/groups/{users}/members"), Some(&session), &format!("username={username}")).await.status()
+            })
+        }).collect();
+        let mut statuses = Vec::new();
+        for demotion in demotions {
+            statuses.push(demotion.await.unwrap());
+        }
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::SEE_OTHER, StatusCode::BAD_REQUEST]);
+
+        let superusers: i64 = sqlx::query_scalar("
+            SELECT COUNT(*) FROM users JOIN groups ON groups.id = users.group_id WHERE groups.superuser;
+        ").fetch_one(&db).await.unwrap();
+        assert_eq!(superusers, 1);
+    }
+
+    #[sqlx::test]
+    async fn audit_log_outlives_groups(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let (sessions, _) = setup(&app, &db, &["alice"]).await;
+
+        let response = post(&app, "/api/groups", Some(&sessions[0]), "name=moderators&description=&colour=%23ff0000").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let response = post(&app, "/api/groups", Some(&sessions[0]), "name=moderators&description=&colour=%23ff0000").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = post(&app, "/api/groups", Some(&sessions[0]), "name=editors&description=&colour=red").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        sqlx::query("DELETE FROM groups WHERE name = 'moderators';").execute(&db).await.unwrap();
+
+        let page = |uri: &'static str| {
+            let request = Request::get(uri)
+                .header(header::COOKIE, format!("session={}", sessions[0]))
+                .body(Body::empty())
+                .unwrap();
+            async {
+                let response = app.clone().oneshot(request).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                String::from_utf8_lossy(&body).into_owned()
+            }
+        };
+        assert!(page("/groups").await.contains("<i>moderators</i> (deleted)"));
+        // Nor is it mistaken for the anonymous user's group
+        assert!(!page("/groups/anonymous").await.contains("moderators"));
+    }
+}
@@ -0,0 +1,555 @@
+use std::collections::BTreeSet;
+use askama_axum::IntoResponse;
+use axum::{extract::{self, State}, response::Redirect, routing::{get, post}, Form, Router};
+use uuid::Uuid;
+
+use crate::{
+    bans::{self, Restriction},
+    error::ResultExt,
+    extractors::{Authentication, Operation::*, Permission, Resource::*, Settings},
+    posts::Rating,
+};
+
+#[derive(askama_axum::Template)]
+#[template(path = "post_history.html")]
+struct HistoryTemplate {
+    signed_in: bool,
+    settings: Settings,
+    post_id: i32,
+    versions: Vec<PostVersion>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PostVersion {
+    pub id: i32,
+    pub editor_id: Option<Uuid>,
+    pub edited_at: time::OffsetDateTime,
+    pub tags_added: Vec<String>,
+    pub tags_removed: Vec<String>,
+    pub pools_added: Vec<i32>,
+    pub pools_removed: Vec<i32>,
+    pub old_source: Option<String>,
+    pub new_source: Option<String>,
+    pub old_rating: Option<Rating>,
+    pub new_rating: Option<Rating>,
+    pub parent_changed: bool,
+    pub old_parent: Option<i32>,
+    pub new_parent: Option<i32>,
+    pub reverts: Option<i32>,
+    /* Additional information */
+    pub editor: String,
+    pub group_colour: Option<String>,
+}
+
+/// The parts of a version needed to undo it.
+#[derive(sqlx::FromRow)]
+struct RevertedVersion {
+    tags_added: Vec<String>,
+    tags_removed: Vec<String>,
+    pools_added: Vec<i32>,
+    pools_removed: Vec<i32>,
+    old_source: Option<String>,
+    old_rating: Option<Rating>,
+    parent_changed: bool,
+    old_parent: Option<i32>,
+}
+
+/// Fields of an edit form. Any field left out is left unchanged.
+#[derive(serde::Deserialize)]
+struct EditForm {
+    tags: Option<String>,
+    source: Option<String>,
+    rating: Option<Rating>,
+    pools: Option<String>,
+    /// A post ID, or empty for no parent
+    parent: Option<String>,
+}
+
+/// The editable parts of a post.
+#[derive(Clone)]
+struct PostState {
+    tags: BTreeSet<String>,
+    pools: BTreeSet<i32>,
+    source: String,
+    rating: Rating,
+    parent: Option<i32>,
+}
+
+/// The difference between two [`PostState`]s, as stored in `post_versions`.
+struct PostEdit {
+    tags_added: Vec<String>,
+    tags_removed: Vec<String>,
+    pools_added: Vec<i32>,
+    pools_removed: Vec<i32>,
+    /// (old, new)
+    source: Option<(String, String)>,
+    /// (old, new)
+    rating: Option<(Rating, Rating)>,
+    /// (old, new)
+    parent: Option<(Option<i32>, Option<i32>)>,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/posts/:id/history", get(history))
+
+        .route("/api/posts/:id/edit", post(edit))
+        .route("/api/posts/:id/revert/:version", post(revert))
+}
+
+impl PostVersion {
+    fn edited_at_rfc2822(&self) -> String {
+        self.edited_at
+            .format(&time::format_description::well_known::Rfc2822)
+            .expect("Couldn't convert edit timestamp to RFC2822 string")
+    }
+
+    fn edited_ago(&self) -> String {
+        timeago::Formatter::new().convert((time::OffsetDateTime::now_utc() - self.edited_at).unsigned_abs())
+    }
+}
+
+impl PostState {
+    /// Reads the current state of a post, locking it against other edits until
+    /// the transaction ends.
+    async fn lock(conn: &mut sqlx::PgConnection, post_id: i32) -> crate::Result<Self> {
+        let (source, rating, parent): (String, Rating, Option<i32>) = sqlx::query_as("
+            SELECT source, rating, parent_id
+            FROM posts
+            WHERE id = $1
+            FOR UPDATE;
+        ")  .bind(post_id)
+            .fetch_one(&mut *conn)
+            .await
+            .on_no_rows(crate::Error::NotFound)?;
+
+        let tags: Vec<String> = sqlx::query_scalar("
+            SELECT t.name
+            FROM post_tags pt
+            JOIN tags t ON pt.tag_id = t.id
+            WHERE pt.post_id = $1;
+        ")  .bind(post_id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        let pools: Vec<i32> = sqlx::query_scalar("
+            SELECT pool_id
+            FROM post_pools
+            WHERE post_id = $1;
+        ")  .bind(post_id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        Ok(Self {
+            tags: tags.into_iter().collect(),
+            pools: pools.into_iter().collect(),
+            source,
+            rating,
+            parent,
+        })
+    }
+}
+
+impl PostEdit {
+    /// Computes the edit that turns `from` into `to`.
+    fn between(from: &PostState, to: &PostState) -> Self {
+        Self {
+            tags_added: to.tags.difference(&from.tags).cloned().collect(),
+            tags_removed: from.tags.difference(&to.tags).cloned().collect(),
+            pools_added: to.pools.difference(&from.pools).copied().collect(),
+            pools_removed: from.pools.difference(&to.pools).copied().collect(),
+            source: (from.source != to.source).then(|| (from.source.clone(), to.source.clone())),
+            rating: (from.rating != to.rating).then_some((from.rating, to.rating)),
+            parent: (from.parent != to.parent).then_some((from.parent, to.parent)),
+        }
+    }
+
+    fn changes_tags(&self) -> bool {
+        !self.tags_added.is_empty() || !self.tags_removed.is_empty()
+    }
+
+    fn changes_pools(&self) -> bool {
+        !self.pools_added.is_empty() || !self.pools_removed.is_empty()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tags_added.is_empty()
+            && self.tags_removed.is_empty()
+            && self.pools_added.is_empty()
+            && self.pools_removed.is_empty()
+            && self.source.is_none()
+            && self.rating.is_none()
+            && self.parent.is_none()
+    }
+
+    /// Applies the edit to a post and records it as a new version. Edits that
+    /// change nothing aren't recorded.
+    async fn apply(
+        self,
+        conn: &mut sqlx::PgConnection,
+        post_id: i32,
+        editor_id: Option<Uuid>,
+        reverts: Option<i32>,
+    ) -> crate::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let unknown_tags: Vec<String> = sqlx::query_scalar("
+            SELECT name
+            FROM UNNEST($1::TEXT[]) AS name
+            WHERE name NOT IN (SELECT name FROM tags);
+        ")  .bind(&self.tags_added)
+            .fetch_all(&mut *conn)
+            .await?;
+        if !unknown_tags.is_empty() {
+            return Err(crate::Error::BadRequest(format!("Unknown tags: {}", unknown_tags.join(", "))));
+        }
+
+        let unknown_pools: Vec<i32> = sqlx::query_scalar("
+            SELECT id
+            FROM UNNEST($1::INTEGER[]) AS id
+            WHERE id NOT IN (SELECT id FROM pools);
+        ")  .bind(&self.pools_added)
+            .fetch_all(&mut *conn)
+            .await?;
+        if !unknown_pools.is_empty() {
+            return Err(crate::Error::BadRequest(String::from("Unknown pool")));
+        }
+
+        sqlx::query("
+            INSERT INTO post_tags (post_id, tag_id)
+            SELECT $1, id
+            FROM tags
+            WHERE name = ANY($2)
+            ON CONFLICT DO NOTHING;
+        ")  .bind(post_id)
+            .bind(&self.tags_added)
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query("
+            DELETE FROM post_tags
+            WHERE post_id = $1
+            AND tag_id IN (SELECT id FROM tags WHERE name = ANY($2));
+        ")  .bind(post_id)
+            .bind(&self.tags_removed)
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query("
+            INSERT INTO post_pools (post_id, pool_id)
+            SELECT $1, UNNEST($2::INTEGER[])
+            ON CONFLICT DO NOTHING;
+        ")  .bind(post_id)
+            .bind(&self.pools_added)
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query("
+            DELETE FROM post_pools
+            WHERE post_id = $1
+            AND pool_id = ANY($2);
+        ")  .bind(post_id)
+            .bind(&self.pools_removed)
+            .execute(&mut *conn)
+            .await?;
+
+        if let Some((_, new_source)) = &self.source {
+            sqlx::query("
+                UPDATE posts SET source = $2 WHERE id = $1;
+            ")  .bind(post_id)
+                .bind(new_source)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        if let Some((_, new_rating)) = self.rating {
+            sqlx::query("
+                UPDATE posts SET rating = $2 WHERE id = $1;
+            ")  .bind(post_id)
+                .bind(new_rating)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        if let Some((_, new_parent)) = self.parent {
+            sqlx::query("
+                UPDATE posts SET parent_id = $2 WHERE id = $1;
+            ")  .bind(post_id)
+                .bind(new_parent)
+                .execute(&mut *conn)
+                .await
+                .on_constraint("parent_not_self", |_| crate::Error::BadRequest(String::from("A post can't be its own parent")))
+                .on_constraint("posts_parent_id_fkey", |_| crate::Error::BadRequest(String::from("Unknown parent post")))?;
+        }
+
+        let (old_source, new_source) = self.source.unzip();
+        let (old_rating, new_rating) = self.rating.unzip();
+        let (old_parent, new_parent) = self.parent.unzip();
+        sqlx::query("
+            INSERT INTO post_versions (
+                post_id, editor_id, tags_added, tags_removed, pools_added,
+                pools_removed, old_source, new_source, old_rating, new_rating,
+                parent_changed, old_parent, new_parent, reverts)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14);
+        ")  .bind(post_id)
+            .bind(editor_id)
+            .bind(&self.tags_added)
+            .bind(&self.tags_removed)
+            .bind(&self.pools_added)
+            .bind(&self.pools_removed)
+            .bind(old_source)
+            .bind(new_source)
+            .bind(old_rating)
+            .bind(new_rating)
+            .bind(self.parent.is_some())
+            .bind(old_parent.flatten())
+            .bind(new_parent.flatten())
+            .bind(reverts)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn history(
+    auth: Authentication,
+    settings: Settings,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<i32>,
+) -> crate::Result<impl IntoResponse> {
+    // Distinguish a post that doesn't exist from one that's never been edited
+    let _: i32 = sqlx::query_scalar("
+        SELECT id FROM posts WHERE id = $1;
+    ")  .bind(id)
+        .fetch_one(&state.db)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+
+    let versions: Vec<PostVersion> = sqlx::query_as("
+        SELECT
+            v.id,
+            v.editor_id,
+            v.edited_at,
+            v.tags_added,
+            v.tags_removed,
+            v.pools_added,
+            v.pools_removed,
+            v.old_source,
+            v.new_source,
+            v.old_rating,
+            v.new_rating,
+            v.parent_changed,
+            v.old_parent,
+            v.new_parent,
+            v.reverts,
+
+            COALESCE(u.username, 'Anonymous') AS editor,
+            g.colour AS group_colour
+        FROM post_versions v
+        LEFT JOIN users u ON u.id = v.editor_id
+        LEFT JOIN groups g ON g.id = u.group_id
+        WHERE v.post_id = $1
+        ORDER BY v.id DESC;
+    ")  .bind(id)
+        .fetch_all(&state.db)
+        .await?;
+
+    Ok(HistoryTemplate {
+        signed_in: auth.signed_in(),
+        settings,
+        post_id: id,
+        versions,
+    })
+}
+
+/// Changing a post's tags, its pools and the rest of it are each allowed
+/// separately. Uploaders may be allowed to change only their own posts.
+async fn check_may_apply(
+    auth: &Authentication,
+    conn: &mut sqlx::PgConnection,
+    post_id: i32,
+    edit: &PostEdit,
+) -> crate::Result<()> {
+    let uploader_id: Option<Uuid> = sqlx::query_scalar("
+        SELECT uploader_id FROM posts WHERE id = $1;
+    ")  .bind(post_id)
+        .fetch_one(conn)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+
+    let changes = [
+        (Tags, edit.changes_tags()),
+        (Pools, edit.changes_pools()),
+        (Posts, edit.source.is_some() || edit.rating.is_some() || edit.parent.is_some()),
+    ];
+    for (resource, changed) in changes {
+        if changed && !auth.has_on(Permission(Update, resource), uploader_id).await? {
+            return Err(crate::Error::Unauthorized);
+        }
+    }
+
+    Ok(())
+}
+
+async fn edit(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<i32>,
+    Form(form): Form<EditForm>,
+) -> crate::Result<Redirect> {
+    let mut tx = state.db.begin().await?;
+    let current = PostState::lock(&mut tx, id).await?;
+
+    let mut desired = current.clone();
+    if let Some(tags) = form.tags {
+        desired.tags = tags.split_whitespace().map(str::to_string).collect();
+    }
+    if let Some(pools) = form.pools {
+        desired.pools = pools.split_whitespace()
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map_err(|_| crate::Error::BadRequest(String::from("Invalid pool ID")))?;
+    }
+    if let Some(source) = form.source {
+        desired.source = source.trim().to_string();
+    }
+    if let Some(rating) = form.rating {
+        desired.rating = rating;
+    }
+    if let Some(parent) = form.parent {
+        desired.parent = match parent.trim() {
+            "" => None,
+            id => Some(id.parse().map_err(|_| crate::Error::BadRequest(String::from("Invalid parent post ID")))?),
+        };
+    }
+
+    let edit = PostEdit::between(&current, &desired);
+    check_may_apply(&auth, &mut tx, id, &edit).await?;
+    if edit.changes_tags() {
+        bans::check_restriction(&auth, Restriction::Tag).await?;
+    }
+    edit.apply(&mut tx, id, auth.id, None).await?;
+    tx.commit().await?;
+
+    Ok(Redirect::to(&format!("/posts/{id}")))
+}
+
+/// Undoes a single version by applying its inverse on top of the post's current
+/// state, recording the result as a new version.
+async fn revert(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    extract::Path((id, version)): extract::Path<(i32, i32)>,
+) -> crate::Result<Redirect> {
+    let mut tx = state.db.begin().await?;
+    let current = PostState::lock(&mut tx, id).await?;
+
+    let reverted: RevertedVersion = sqlx::query_as("
+        SELECT tags_added, tags_removed, pools_added, pools_removed, old_source, old_rating,
+            parent_changed, old_parent
+        FROM post_versions
+        WHERE id = $1
+        AND post_id = $2;
+    ")  .bind(version)
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+
+    let mut desired = current.clone();
+    for tag in &reverted.tags_added {
+        desired.tags.remove(tag);
+    }
+    desired.tags.extend(reverted.tags_removed);
+    for pool in &reverted.pools_added {
+        desired.pools.remove(pool);
+    }
+    desired.pools.extend(reverted.pools_removed);
+    if let Some(source) = reverted.old_source {
+        desired.source = source;
+    }
+    if let Some(rating) = reverted.old_rating {
+        desired.rating = rating;
+    }
+    if reverted.parent_changed {
+        desired.parent = reverted.old_parent;
+    }
+
+    let edit = PostEdit::between(&current, &desired);
+    check_may_apply(&auth, &mut tx, id, &edit).await?;
+    if edit.changes_tags() {
+        bans::check_restriction(&auth, Restriction::Tag).await?;
+    }
+    edit.apply(&mut tx, id, auth.id, Some(version)).await?;
+    tx.commit().await?;
+
+    Ok(Redirect::to(&format!("/posts/{id}/history")))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{Request, StatusCode}};
+    use tower::ServiceExt;
+
+    use crate::testing::{app, post, session_token};
+
+    async fn post_state(db: &sqlx::PgPool, id: i32) -> (Vec<String>, String, Option<i32>) {
+        sqlx::query_as("
+            SELECT
+                ARRAY(SELECT t.name FROM post_tags pt JOIN tags t ON t.id = pt.tag_id WHERE pt.post_id = p.id),
+                p.rating::TEXT,
+                p.parent_id
+            FROM posts p
+            WHERE p.id = $1;
+        ").bind(id).fetch_one(db).await.unwrap()
+    }
+
+    #[sqlx::test]
+    async fn edits_can_be_reverted(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let token = session_token(&response);
+        sqlx::query("
+            INSERT INTO permissions (group_id, operation, resource, own_only)
+            SELECT id, 'update', resource, false
+            FROM groups, UNNEST(ARRAY['posts', 'tags']::RESOURCE[]) AS resource
+            WHERE name = 'users';
+        ").execute(&db).await.unwrap();
+        sqlx::query("
+            WITH category AS (
+                INSERT INTO tag_categories (name, colour) VALUES ('general', '#000000') RETURNING id
+            )
+            INSERT INTO tags (name, category) SELECT 'cat', id FROM category;
+        ").execute(&db).await.unwrap();
+        let posts: Vec<i32> = sqlx::query_scalar("
+            INSERT INTO posts (md5, width, height, media_type, file_size, media_path, thumbnail_path)
+            VALUES ('0', 1, 1, 'image', 1, 'a.png', 'a.webp'), ('1', 1, 1, 'image', 1, 'b.png', 'b.webp')
+            RETURNING id;
+        ").fetch_all(&db).await.unwrap();
+        let (parent, child) = (posts[0], posts[1]);
+
+        let response = post(&app, &format!("/api/posts/{child}/edit"), Some(&token), &format!("tags=cat&rating=explicit&parent={parent}")).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(post_state(&db, child).await, (vec![String::from("cat")], String::from("explicit"), Some(parent)));
+
+        let request = Request::get(format!("/posts/{child}/history")).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("+cat"));
+        assert!(body.contains(&format!("post {parent}")));
+
+        let version: i32 = sqlx::query_scalar("SELECT id FROM post_versions;").fetch_one(&db).await.unwrap();
+        let response = post(&app, &format!("/api/posts/{child}/revert/{version}"), Some(&token), "").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(post_state(&db, child).await, (vec![], String::from("safe"), None));
+
+        let reverts: Option<i32> = sqlx::query_scalar("
+            SELECT reverts FROM post_versions WHERE id <> $1;
+        ").bind(version).fetch_one(&db).await.unwrap();
+        assert_eq!(reverts, Some(version));
+    }
+}
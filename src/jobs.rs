@@ -0,0 +1,366 @@
+//! Background jobs, queued in Postgres and run by workers in the server
+//! process. Workers claim jobs with `FOR UPDATE SKIP LOCKED`, so any number of
+//! them, across any number of server processes, never run a job twice at once.
+
+use std::time::Duration;
+use askama_axum::IntoResponse;
+use axum::{extract::{self, State}, response::Redirect, routing::{get, post}, Router};
+use tokio::sync::Notify;
+
+use crate::{
+    error::ResultExt,
+    extractors::{Authentication, Settings},
+    groups::require_superuser,
+    posts::PostState,
+    schema::all_variants,
+};
+
+/// Attempts at a job before it's marked failed
+const MAX_ATTEMPTS: i32 = 5;
+/// Wait before the first retry, doubled for each retry after it
+const RETRY_DELAY_SECONDS: f64 = 30.;
+/// How often idle workers check for jobs due for a retry, or queued by
+/// another server process
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[sqlx(type_name = "JOB_KIND", rename_all = "lowercase")]
+pub enum JobKind {
+    /// Makes a post's thumbnail, then marks it ready
+    Thumbnail,
+}
+
+/// Wakes idle workers when a job is queued
+#[derive(Default)]
+pub struct Queue {
+    queued: Notify,
+}
+
+#[derive(sqlx::FromRow)]
+struct Job {
+    pub id: i32,
+    pub kind: JobKind,
+    pub post_id: i32,
+    pub attempts: i32,
+    /* Additional information */
+    pub media_path: String,
+    pub thumbnail_path: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct FailedJob {
+    pub id: i32,
+    pub kind: JobKind,
+    pub post_id: i32,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub failed_at: time::OffsetDateTime,
+}
+
+#[derive(askama_axum::Template)]
+#[template(path = "jobs.html")]
+struct JobsTemplate {
+    signed_in: bool,
+    settings: Settings,
+    failed: Vec<FailedJob>,
+    /// Jobs waiting to run or running, not counting failed ones
+    pending_count: i64,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/jobs", get(jobs_page))
+
+        .route("/api/jobs/:id/retry", post(retry))
+}
+
+/// Queues a job. It's only seen by workers once the transaction commits, and
+/// they're only woken by [`Queue::wake`].
+pub async fn enqueue(conn: &mut sqlx::PgConnection, kind: JobKind, post_id: i32) -> crate::Result<()> {
+    sqlx::query("
+        INSERT INTO jobs (kind, post_id)
+        VALUES ($1, $2);
+    ")  .bind(kind)
+        .bind(post_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Runs jobs as they're queued, forever
+pub async fn work(state: crate::State) {
+    loop {
+        match run_next(&state).await {
+            // There might be more
+            Ok(true) => continue,
+            Ok(false) => (),
+            Err(e) => log::error!("Couldn't run job: {e:?}"),
+        }
+
+        tokio::select! {
+            _ = state.jobs.queued.notified() => (),
+            _ = tokio::time::sleep(POLL_INTERVAL) => (),
+        }
+    }
+}
+
+/// Claims and runs the job that's been due longest, if any, returning whether
+/// there was one
+pub(crate) async fn run_next(state: &crate::State) -> crate::Result<bool> {
+    // Claiming a job pushes it back far enough to run, so it's left alone by
+    // other workers without holding a transaction open. If this worker dies
+    // with it, it's picked up again after that. FFmpeg's timeout includes
+    // waiting for a slot, so this is only outlived by FFmpeg overrunning it,
+    // and the attempt is checked to still be the latest before its result is
+    // recorded.
+    let lease = 2. * state.config.uploads.processing_timeout_seconds as f64;
+    let job: Option<Job> = sqlx::query_as("
+        WITH claimed AS (
+            UPDATE jobs
+            SET attempts = attempts + 1, run_at = CURRENT_TIMESTAMP + make_interval(secs => $1)
+            WHERE id = (
+                SELECT id
+                FROM jobs
+                WHERE failed_at IS NULL
+                AND run_at <= CURRENT_TIMESTAMP
+                ORDER BY run_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, kind, post_id, attempts
+        )
+        SELECT claimed.*, posts.media_path, posts.thumbnail_path
+        FROM claimed
+        JOIN posts ON posts.id = claimed.post_id;
+    ")  .bind(lease)
+        .fetch_optional(&state.db)
+        .await?;
+    let Some(job) = job else {
+        return Ok(false);
+    };
+
+    let result = match job.kind {
+        JobKind::Thumbnail => state.media.thumbnail(
+            state.config.data.media().join(&job.media_path),
+            state.config.data.thumbnails().join(&job.thumbnail_path),
+            state.config.data.thumbnails.resolution,
+        ).await,
+    };
+
+    let mut tx = state.db.begin().await?;
+    let attempts: Option<i32> = sqlx::query_scalar("
+        SELECT attempts FROM jobs WHERE id = $1 FOR UPDATE;
+    ")  .bind(job.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if attempts != Some(job.attempts) {
+        log::warn!("{} job for post {} outlived its lease on attempt {}", job.kind, job.post_id, job.attempts);
+        return Ok(true);
+    }
+
+    match result {
+        Ok(()) => {
+            sqlx::query("DELETE FROM jobs WHERE id = $1;")
+                .bind(job.id)
+                .execute(&mut *tx)
+                .await?;
+            set_post_state(&mut tx, job.post_id, PostState::Ready).await?;
+        },
+        Err(e) => {
+            let error = describe(&e);
+            log::warn!("{} job for post {} failed on attempt {}: {error}", job.kind, job.post_id, job.attempts);
+
+            if job.attempts >= MAX_ATTEMPTS {
+                sqlx::query("
+                    UPDATE jobs
+                    SET last_error = $2, failed_at = CURRENT_TIMESTAMP
+                    WHERE id = $1;
+                ")  .bind(job.id)
+                    .bind(&error)
+                    .execute(&mut *tx)
+                    .await?;
+                set_post_state(&mut tx, job.post_id, PostState::Failed).await?;
+            } else {
+                let delay = RETRY_DELAY_SECONDS * 2f64.powi(job.attempts - 1);
+                sqlx::query("
+                    UPDATE jobs
+                    SET last_error = $2, run_at = CURRENT_TIMESTAMP + make_interval(secs => $3)
+                    WHERE id = $1;
+                ")  .bind(job.id)
+                    .bind(&error)
+                    .bind(delay)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        },
+    }
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+async fn set_post_state(conn: &mut sqlx::PgConnection, post_id: i32, state: PostState) -> crate::Result<()> {
+    sqlx::query("UPDATE posts SET state = $2 WHERE id = $1;")
+        .bind(post_id)
+        .bind(state)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Explains why a job failed, for superusers
+fn describe(e: &crate::Error) -> String {
+    match e {
+        crate::Error::UnsupportedMediaType(s) | crate::Error::BadRequest(s) => s.clone(),
+        // Errors given context by FFmpeg work are wrapped in one of our own
+        crate::Error::Internal(e) => e.chain()
+            .map(|cause| match cause.downcast_ref::<crate::Error>() {
+                Some(e) => describe(e),
+                None => cause.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(": "),
+        crate::Error::Sql(e) => e.to_string(),
+        e => e.to_string(),
+    }
+}
+
+async fn jobs_page(
+    auth: Authentication,
+    settings: Settings,
+) -> crate::Result<impl IntoResponse> {
+    require_superuser(&auth).await?;
+
+    let failed: Vec<FailedJob> = sqlx::query_as("
+        SELECT id, kind, post_id, attempts, last_error, failed_at
+        FROM jobs
+        WHERE failed_at IS NOT NULL
+        ORDER BY failed_at DESC;
+    ")  .fetch_all(&auth.db)
+        .await?;
+
+    let pending_count: i64 = sqlx::query_scalar("
+        SELECT COUNT(*) FROM jobs WHERE failed_at IS NULL;
+    ")  .fetch_one(&auth.db)
+        .await?;
+
+    Ok(JobsTemplate {
+        signed_in: true,
+        settings,
+        failed,
+        pending_count,
+    })
+}
+
+/// Gives a failed job its attempts back
+async fn retry(
+    auth: Authentication,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<i32>,
+) -> crate::Result<Redirect> {
+    require_superuser(&auth).await?;
+
+    let mut tx = auth.db.begin().await?;
+    let post_id: i32 = sqlx::query_scalar("
+        UPDATE jobs
+        SET attempts = 0, run_at = CURRENT_TIMESTAMP, failed_at = NULL
+        WHERE id = $1
+        AND failed_at IS NOT NULL
+        RETURNING post_id;
+    ")  .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+    set_post_state(&mut tx, post_id, PostState::Processing).await?;
+    tx.commit().await?;
+    state.jobs.wake();
+
+    Ok(Redirect::to("/jobs"))
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wakes a worker, if one's idle, or otherwise the next to go idle
+    pub fn wake(&self) {
+        self.queued.notify_one();
+    }
+}
+
+all_variants!(JobKind { Thumbnail });
+
+impl std::fmt::Display for JobKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            JobKind::Thumbnail => "thumbnail",
+        })
+    }
+}
+
+impl FailedJob {
+    fn failed_at_rfc2822(&self) -> String {
+        self.failed_at
+            .format(&time::format_description::well_known::Rfc2822)
+            .expect("Couldn't convert job timestamp to RFC2822 string")
+    }
+
+    fn failed_ago(&self) -> String {
+        timeago::Formatter::new().convert((time::OffsetDateTime::now_utc() - self.failed_at).unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{posts::PostState, testing::state_with};
+
+    async fn job(db: &sqlx::PgPool) -> (i32, Option<String>, bool) {
+        sqlx::query_as("
+            SELECT attempts, last_error, failed_at IS NOT NULL FROM jobs;
+        ").fetch_one(db).await.unwrap()
+    }
+
+    async fn post_state(db: &sqlx::PgPool) -> PostState {
+        sqlx::query_scalar("SELECT state FROM posts;").fetch_one(db).await.unwrap()
+    }
+
+    #[sqlx::test]
+    async fn failing_jobs_back_off_then_fail(db: sqlx::PgPool) {
+        let state = state_with(db.clone(), "");
+        // Its media doesn't exist, so thumbnailing it always fails
+        let mut tx = db.begin().await.unwrap();
+        let post_id: i32 = sqlx::query_scalar("
+            INSERT INTO posts (md5, width, height, media_type, file_size, media_path, thumbnail_path, state)
+            VALUES ('0', 1, 1, 'image', 1, 'missing.png', 'missing.webp', 'processing')
+            RETURNING id;
+        ").fetch_one(&mut *tx).await.unwrap();
+        super::enqueue(&mut tx, super::JobKind::Thumbnail, post_id).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(super::run_next(&state).await.unwrap());
+        let (attempts, last_error, failed) = job(&db).await;
+        assert_eq!(attempts, 1);
+        assert!(last_error.is_some());
+        assert!(!failed);
+        assert_eq!(post_state(&db).await, PostState::Processing);
+
+        // Backing off
+        assert!(!super::run_next(&state).await.unwrap());
+
+        sqlx::query("
+            UPDATE jobs SET attempts = $1 - 1, run_at = CURRENT_TIMESTAMP;
+        ").bind(super::MAX_ATTEMPTS).execute(&db).await.unwrap();
+        assert!(super::run_next(&state).await.unwrap());
+        let (attempts, _, failed) = job(&db).await;
+        assert_eq!(attempts, super::MAX_ATTEMPTS);
+        assert!(failed);
+        assert_eq!(post_state(&db).await, PostState::Failed);
+
+        // Failed jobs are left for a superuser to retry
+        sqlx::query("UPDATE jobs SET run_at = CURRENT_TIMESTAMP;").execute(&db).await.unwrap();
+        assert!(!super::run_next(&state).await.unwrap());
+    }
+}
@@ -1,30 +1,55 @@
 #![feature(try_blocks, result_flattening)]
 extern crate ffmpeg_next as ffmpeg;
-use std::{fs, sync::Arc};
+use std::{fs, net::SocketAddr, sync::Arc, time::Duration};
 use askama_axum::IntoResponse;
-use axum::{extract::{self, DefaultBodyLimit}, routing::get};
-use extractors::Authentication;
+use axum::{extract::{self, DefaultBodyLimit}, middleware, routing::get};
+use extractors::{Authentication, Settings};
 use tower_http::services::ServeDir;
 
 mod extractors;
 mod traits;
 mod posts;
+mod history;
+mod users;
+mod settings;
 mod auth;
+mod account;
+mod registration;
+mod two_factor;
+mod oidc;
+mod password;
+mod media;
+mod jobs;
+mod cache;
+mod tokens;
+mod csrf;
+mod groups;
+mod bans;
 mod config;
 mod query;
+mod throttle;
+mod schema;
 mod error;
+#[cfg(test)]
+mod testing;
 use error::{Result, Error};
 
 #[derive(Clone)]
 struct State {
     config: Arc<config::Config>,
     db: sqlx::PgPool,
+    auth_cache: Arc<cache::AuthCache>,
+    hasher: Arc<password::Hasher>,
+    oidc: Arc<oidc::Provider>,
+    media: Arc<media::Workers>,
+    jobs: Arc<jobs::Queue>,
 }
 
 #[derive(askama_axum::Template)]
 #[template(path = "index.html")]
 struct Index {
     signed_in: bool,
+    settings: Settings,
     post_count: i64,
     data_size: String,
 }
@@ -52,25 +77,30 @@ async fn main() -> anyhow::Result<()> {
 
     let db = sqlx::PgPool::connect(&config.network.database).await?;
     sqlx::migrate!().run(&db).await?;
+    schema::check(&db).await?;
+    let default_group: Option<i32> = sqlx::query_scalar("SELECT id FROM groups WHERE name = $1;")
+        .bind(&config.accounts.default_group)
+        .fetch_optional(&db)
+        .await?;
+    if default_group.is_none() {
+        anyhow::bail!("Default group '{}' doesn't exist", config.accounts.default_group);
+    }
 
-    let app = axum::Router::new()
-        .nest_service("/static", ServeDir::new("static"))
-        .nest_service("/static/thumb", ServeDir::new(config.data.thumbnails()))
-        .nest_service("/static/media", ServeDir::new(config.data.media()))
-        
-        .route("/", get(index))
-        .route("/settings", get(settings))
-        .merge(posts::routes())
-        .merge(auth::routes())
-        .layer(DefaultBodyLimit::disable())
-        .with_state(State {
-            config: Arc::clone(&config),
-            db: db.clone(),
-        });
+    let state = State {
+        config: Arc::clone(&config),
+        db: db.clone(),
+        auth_cache: Arc::new(cache::AuthCache::new(Duration::from_secs(config.accounts.auth_cache_seconds))),
+        hasher: Arc::new(password::Hasher::new(&config.accounts)?),
+        oidc: Arc::new(oidc::Provider::new()),
+        media: Arc::new(media::Workers::new(&config.uploads)),
+        jobs: Arc::new(jobs::Queue::new()),
+    };
+
+    let app = app(state.clone());
 
     let account_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users;").fetch_one(&db).await?;
     if account_count == 0 {
-        let password = auth::kdf(&config.accounts.initial_superuser_password);
+        let password = state.hasher.hash(config.accounts.initial_superuser_password.clone()).await?;
         sqlx::query("
             WITH superuser_group_id AS (
                 INSERT INTO groups (name, superuser)
@@ -86,16 +116,50 @@ async fn main() -> anyhow::Result<()> {
 
     log::debug!("FFmpeg build information: {}", ffmpeg::codec::configuration());
 
+    tokio::spawn(auth::purge_sessions(state.clone()));
+    for _ in 0..config.uploads.processing_threads.max(1) {
+        tokio::spawn(jobs::work(state.clone()));
+    }
+    tokio::spawn(cache::listen(state));
+
     let listener = tokio::net::TcpListener::bind(&config.network.bind).await?;
     log::info!("Serving on http://{}", config.network.bind);
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
 
+/// Builds the complete application router
+fn app(state: State) -> axum::Router {
+    axum::Router::new()
+        .nest_service("/static", ServeDir::new("static"))
+        .nest_service("/static/thumb", ServeDir::new(state.config.data.thumbnails()))
+        .nest_service("/static/media", ServeDir::new(state.config.data.media()))
+        
+        .route("/", get(index))
+        .merge(posts::routes())
+        .merge(history::routes())
+        .merge(users::routes())
+        .merge(settings::routes())
+        .merge(auth::routes())
+        .merge(account::routes())
+        .merge(registration::routes())
+        .merge(two_factor::routes())
+        .merge(oidc::routes())
+        .merge(tokens::routes())
+        .merge(groups::routes())
+        .merge(bans::routes())
+        .merge(jobs::routes())
+        // Uploads are limited while they stream in, by `posts::save_multipart_file`
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn(csrf::protect))
+        .with_state(state)
+}
+
 async fn index(
     extract::State(state): extract::State<crate::State>,
     auth: Authentication,
+    settings: Settings,
 ) -> crate::Result<impl IntoResponse> {
     let (post_count, data_size): (i64, i64) = sqlx::query_scalar("
         SELECT (COUNT(*)::BIGINT, COALESCE(SUM(posts.file_size), 0)::BIGINT)
@@ -105,16 +169,12 @@ async fn index(
 
     Ok(Index {
         signed_in: auth.signed_in(),
+        settings,
         post_count,
         data_size,
     })
 }
 
-async fn settings(
-) -> impl IntoResponse {
-    "Imagine a settings page here"
-}
-
 fn readable_file_size(raw: u64) -> anyhow::Result<String> {
     let mut raw = raw as f64;
     for unit in &["", "Ki", "Mi", "Gi"] {
@@ -0,0 +1,169 @@
+//! FFmpeg work, which blocks for as long as the media takes to decode, so it's
+//! kept off the async workers.
+
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+use anyhow::Context;
+use ffmpeg::{codec::{self, context::Context as CodecContext}, frame::Video, software::scaling::context::Context as ScalingContext, Rescale};
+use tokio::{fs, sync::Semaphore};
+
+use crate::config::Uploads;
+
+/// Runs FFmpeg jobs on Tokio's blocking pool. At most `processing-threads`
+/// run at once, and each is given up on after `processing-timeout-seconds`.
+pub struct Workers {
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl Workers {
+    pub fn new(uploads: &Uploads) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(uploads.processing_threads.max(1))),
+            timeout: Duration::from_secs(uploads.processing_timeout_seconds),
+        }
+    }
+
+    /// Runs `f` on the blocking pool once a slot is free. The timeout includes
+    /// waiting for the slot, so callers like the job queue can rely on it.
+    /// FFmpeg can't be interrupted, so a job that times out keeps its slot
+    /// until it finishes and can't starve the pool of more threads.
+    async fn run<T: Send + 'static>(&self, f: impl FnOnce() -> crate::Result<T> + Send + 'static) -> crate::Result<T> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        let permit = match tokio::time::timeout_at(deadline, Arc::clone(&self.permits).acquire_owned()).await {
+            Ok(permit) => permit.map_err(anyhow::Error::from)?,
+            Err(_) => return Err(crate::Error::Internal(anyhow::anyhow!("Timed out waiting for other media to finish processing"))),
+        };
+        let job = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        });
+
+        match tokio::time::timeout_at(deadline, job).await {
+            Ok(result) => result.map_err(anyhow::Error::from)?,
+            Err(_) => Err(crate::Error::UnsupportedMediaType(String::from("Media took too long to process"))),
+        }
+    }
+
+    /// Returns the width and height of the media's best video stream
+    pub async fn dimensions(&self, path: PathBuf) -> crate::Result<(i32, i32)> {
+        self.run(move || media_dimensions(path)).await
+    }
+
+    /// Writes a WebP thumbnail of a frame from the middle of the media, no
+    /// larger than `res` either way
+    pub async fn thumbnail(&self, src: PathBuf, dst: PathBuf, res: u32) -> crate::Result<()> {
+        fs::create_dir_all(dst.parent().expect("thumbnail called with invalid dst")).await?;
+
+        self.run(move || {
+            let original_frame = first_frame(&src).context("Couldn't extract first frame of uploaded media")?;
+            let scaled_frame = scale_frame(original_frame, res)?;
+            write_frame(scaled_frame, &dst).context("Couldn't write out computed frame")?;
+
+            Ok(())
+        }).await
+    }
+}
+
+/// TODO: It might be worth reusing the ictx/input/decoder from this function
+/// in create_thumbnail etc. Not sure of the performance implication of
+/// parsing the file twice for this.
+fn media_dimensions<P: AsRef<Path>>(path: P) -> crate::Result<(i32, i32)> {
+    let ictx = ffmpeg::format::input(&path)?;
+    let input = ictx.streams().best(ffmpeg::media::Type::Video)
+        .ok_or(crate::Error::BadRequest("Media has no streams".to_string()))?;
+    let context_decoder = CodecContext::from_parameters(input.parameters())?;
+    let decoder = context_decoder.decoder().video()?;
+
+    Ok((decoder.width() as i32, decoder.height() as i32))
+}
+
+/// Returns the first full frame from the input.
+fn first_frame(src: &Path) -> crate::Result<Video> {
+    let mut ictx = ffmpeg::format::input(&src)?;
+    let seek_sec = (ictx.duration() / 2).rescale((1, 1), ffmpeg::rescale::TIME_BASE);
+    ictx.seek(seek_sec, ..seek_sec)?;
+
+    let input = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .ok_or(crate::Error::BadRequest("Media has no streams".to_string()))?;
+    let video_stream_index = input.index();
+
+    let context_decoder = CodecContext::from_parameters(input.parameters())?;
+    let mut decoder = context_decoder.decoder().video()?;
+
+    // I'm not sure if there's an opportunity for a DOS by sending a large video
+    // that contains no full frames so I'll play it safe.
+    let mut frames_decoded = 0;
+    for (stream, packet) in ictx.packets() {
+        if stream.index() == video_stream_index {
+            let mut frame = Video::empty();
+            while decoder.receive_frame(&mut frame).is_err() {
+                decoder.send_packet(&packet)?;
+                if frames_decoded > 10 {
+                    return Err(crate::Error::UnsupportedMediaType(
+                        "Couldn't decode a thumbnail frame".to_string()
+                    ))
+                }
+
+                frames_decoded += 1;
+            };
+
+            return Ok(frame);
+        }
+    }
+
+    Err(crate::Error::UnsupportedMediaType("couldn't decode a frame".to_string()))
+}
+
+fn scale_frame(frame: Video, to: u32) -> crate::Result<Video> {
+    // Keep in sync with the thumbnail_dimensions SQL function
+    let (sw, sh) = (frame.width(), frame.height());
+    let ratio = (to as f32 / sw as f32).min(to as f32 / sh as f32);
+    let (w, h) = ((sw as f32 * ratio) as u32, (sh as f32 * ratio) as u32);
+
+    let mut scaler = ScalingContext::get(
+        frame.format(),
+        sw, sh,
+        ffmpeg::format::Pixel::YUV420P,
+        w, h,
+        ffmpeg::software::scaling::flag::Flags::BILINEAR,
+    )?;
+
+    let mut scaled_frame = Video::empty();
+    scaler.run(&frame, &mut scaled_frame)?;
+    scaled_frame.set_pts(Some(0));
+
+    Ok(scaled_frame)
+}
+
+// TODO: use avif instead of webp
+fn write_frame(frame: Video, dst: &Path) -> crate::Result<()> {
+    let webp_encoder = codec::encoder::find(codec::id::Id::WEBP)
+        .expect("ffmpeg couldn't find thumbnail codec encoder");
+    let codec_ctx = CodecContext::new_with_codec(webp_encoder);
+    let mut encoder = codec_ctx.encoder().video()?;
+
+    encoder.set_height(frame.height());
+    encoder.set_width(frame.width());
+    encoder.set_format(frame.format());
+    encoder.set_time_base(ffmpeg::Rational::new(1, 1));
+    let mut opened_encoder = encoder.open()?;
+
+    let mut output = ffmpeg::format::output(dst)?;
+    let mut output_stream = output.add_stream(webp_encoder)?;
+    output_stream.set_parameters(&opened_encoder);
+
+    output.write_header()?;
+    opened_encoder.send_frame(&frame).context("Couldn't send decoded frame to thumbnail encoder")?;
+    opened_encoder.send_eof()?;
+
+    let mut encoded = ffmpeg::Packet::empty();
+    while opened_encoder.receive_packet(&mut encoded).is_ok() {
+        encoded.write(&mut output)?;
+    }
+
+    output.write_trailer()?;
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use argon2::{password_hash::{rand_core::OsRng, SaltString}, Algorithm, Argon2, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::config::Accounts;
+
+/// Hashes and verifies passwords on Tokio's blocking pool, so Argon2 doesn't
+/// stall the async workers. At most `hashing-threads` run at once, since each
+/// one takes a lot of memory.
+pub struct Hasher {
+    argon2: Argon2<'static>,
+    permits: Arc<Semaphore>,
+    /// Verified against when signing in to a username that doesn't exist, so
+    /// it takes as long as a real one
+    dummy: String,
+}
+
+impl Hasher {
+    pub fn new(accounts: &Accounts) -> anyhow::Result<Self> {
+        let params = argon2::Params::new(
+            accounts.argon2_memory_kib,
+            accounts.argon2_iterations,
+            accounts.argon2_parallelism,
+            None,
+        ).map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {e}"))?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+        let dummy = argon2.hash_password(b"", &SaltString::generate(&mut OsRng))
+            .map_err(|e| anyhow::anyhow!("Couldn't hash password: {e}"))?
+            .to_string();
+
+        Ok(Self {
+            argon2,
+            permits: Arc::new(Semaphore::new(accounts.hashing_threads.max(1))),
+            dummy,
+        })
+    }
+
+    /// Runs `f` on the blocking pool once a hashing slot is free. The slot is
+    /// held until `f` finishes, even if the request waiting on it goes away.
+    async fn run<T: Send + 'static>(&self, f: impl FnOnce(&Argon2) -> T + Send + 'static) -> crate::Result<T> {
+        let permit = Arc::clone(&self.permits).acquire_owned().await.map_err(anyhow::Error::from)?;
+        let argon2 = self.argon2.clone();
+
+        Ok(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f(&argon2)
+        }).await.map_err(anyhow::Error::from)?)
+    }
+
+    pub async fn hash(&self, password: String) -> crate::Result<String> {
+        self.run(move |argon2| -> crate::Result<String> {
+            Ok(argon2.hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+                .map_err(|e| anyhow::anyhow!("Couldn't hash password: {e}"))?
+                .to_string())
+        }).await?
+    }
+
+    /// Checks a password against a stored hash. The hash's own parameters are
+    /// used, so hashes made before a parameter change still verify.
+    pub async fn verify(&self, password: String, hash: String) -> crate::Result<bool> {
+        self.run(move |argon2| -> crate::Result<bool> {
+            let hash = PasswordHash::new(&hash)
+                .map_err(|e| anyhow::anyhow!("Invalid stored password hash: {e}"))?;
+
+            Ok(argon2.verify_password(password.as_bytes(), &hash).is_ok())
+        }).await?
+    }
+
+    /// Wastes as much time as [`Hasher::verify`] would
+    pub async fn verify_dummy(&self, password: String) -> crate::Result<()> {
+        self.verify(password, self.dummy.clone()).await.map(|_| ())
+    }
+
+    /// Whether a stored hash was made with different parameters than the
+    /// configured ones, and should be replaced next time the password's known.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return true;
+        };
+        let Ok(params) = argon2::Params::try_from(&hash) else {
+            return true;
+        };
+
+        let current = self.argon2.params();
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != current.m_cost()
+            || params.t_cost() != current.t_cost()
+            || params.p_cost() != current.p_cost()
+    }
+}
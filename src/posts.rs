@@ -1,9 +1,7 @@
 use std::{os::unix::fs::MetadataExt, path::{Path, PathBuf}};
-use anyhow::Context;
 use argon2::password_hash::rand_core::{self, RngCore};
-use ffmpeg::{codec::{self, context::Context as CodecContext}, frame::Video, software::scaling::context::Context as ScalingContext, Rescale};
 use askama_axum::IntoResponse;
-use axum::{extract::{self, multipart::Field, Multipart, State}, routing::{get, post}, Json, Router};
+use axum::{extract::{self, multipart::Field, Multipart, State}, response::Redirect, routing::{get, post}, Json, Router};
 use hex::ToHex;
 use md5::Digest;
 use serde::{Deserialize, Serialize};
@@ -12,34 +10,68 @@ use infer::MatcherType;
 use uuid::Uuid;
 
 use crate::{
-    extractors::{Authentication, Operation::*, Permission, Resource::*},
-    query::Query,
+    bans::{self, Restriction},
+    error::ResultExt,
+    extractors::{Authentication, Operation::*, Permission, Resource::*, Settings},
+    jobs::{self, JobKind},
+    query::{Blacklist, Query},
+    schema::all_variants,
 };
 
-#[derive(PartialEq, sqlx::Type, Deserialize)]
+#[derive(Clone, Copy, PartialEq, sqlx::Type, Deserialize)]
 #[sqlx(type_name = "MEDIA_TYPE", rename_all = "lowercase")]
-enum MediaType {
+pub(crate) enum MediaType {
     Image,
     Video,
 }
 
+/// Whether a post's thumbnail has been made yet. Until it has, it's shown with
+/// a placeholder.
+#[derive(Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[sqlx(type_name = "POST_STATE", rename_all = "lowercase")]
+pub enum PostState {
+    Processing,
+    Ready,
+    Failed,
+}
+
+/// Ordered from least to most explicit, so ratings can be compared with `<=`.
+#[derive(Clone, Copy, PartialEq, PartialOrd, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(type_name = "RATING", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Safe,
+    Questionable,
+    Explicit,
+}
+
 #[derive(askama_axum::Template)]
 #[template(path = "posts.html")]
 struct PostsTemplate {
     signed_in: bool,
+    settings: Settings,
     results: Vec<QueriedPosts>,
+    query: String,
+    page: u32,
+    has_next: bool,
+    reveal: bool,
+    /// Number of posts hidden by the user's blacklist
+    hidden_count: i64,
 }
 
-#[derive(sqlx::FromRow)]
-struct QueriedPosts {
-    url: String,
-    thumbnail_path: String,
+#[derive(sqlx::FromRow, Serialize)]
+pub struct QueriedPosts {
+    pub url: String,
+    pub thumbnail_path: String,
+    pub thumbnail_width: i32,
+    pub thumbnail_height: i32,
 }
 
 #[derive(askama_axum::Template)]
 #[template(path = "post.html")]
 struct PostTemplate {
     signed_in: bool,
+    settings: Settings,
     post: PostInformation,
     tags: Vec<PostTag>,
     pools: Vec<(i32, String)>,
@@ -52,6 +84,32 @@ struct PostTemplate {
 #[template(path = "upload.html")]
 struct UploadTemplate {
     signed_in: bool,
+    settings: Settings,
+    max_files_per_request: usize,
+    max_file_size: String,
+    quota: Quota,
+}
+
+/// What a user may still upload today. Anonymous uploads have no quota.
+#[derive(Default)]
+struct Quota {
+    /// `None` if there's no limit
+    files_left: Option<i64>,
+    /// `None` if there's no limit
+    bytes_left: Option<i64>,
+}
+
+/// Most bytes a file may have, and the error given if it has more
+struct SizeLimit {
+    bytes: u64,
+    exceeded: String,
+}
+
+/// Deletes a file when dropped, unless it's kept, so a failed upload leaves
+/// nothing behind however it fails
+struct TempFile {
+    path: PathBuf,
+    kept: bool,
 }
 
 #[derive(Serialize)]
@@ -59,9 +117,9 @@ struct UploadResponse {
     post_id: i32,
 }
 
-#[derive(sqlx::Type)]
+#[derive(Clone, Copy, sqlx::Type)]
 #[sqlx(type_name = "POST_VOTE", rename_all = "lowercase")]
-enum PostVote {
+pub(crate) enum PostVote {
     Like,
     Dislike,
 }
@@ -78,6 +136,8 @@ struct PostInformation {
     pub media_type: MediaType,
     pub file_size: i64,
     pub media_path: String,
+    pub rating: Rating,
+    pub parent_id: Option<i32>,
     /* Additional information */
     pub uploader: String,
     pub group_colour: Option<String>,
@@ -139,6 +199,8 @@ async fn get_post_info(db: &sqlx::PgPool, id: i32) -> crate::Result<(PostInforma
             post_info.media_type,
             post_info.file_size,
             ('/static/media/' || post_info.media_path) AS media_path,
+            post_info.rating,
+            post_info.parent_id,
 
             COALESCE(user_info.uploader, 'Anonymous') AS uploader,
             COALESCE(post_score.score, 0) AS score,
@@ -156,7 +218,7 @@ async fn get_post_info(db: &sqlx::PgPool, id: i32) -> crate::Result<(PostInforma
         .await?;
 
     let tags: Vec<PostTag> = sqlx::query_as("
-        SELECT t.name AS name, c.colour AS colour, COUNT(pt_all.post_id) AS count, c.rank AS rank
+        SELECT t.name AS name, c.colour AS colour, COUNT(pt_all.post_id)::INTEGER AS count, c.rank AS rank
         FROM post_tags pt
         JOIN tags t
             ON pt.tag_id = t.id
@@ -181,10 +243,57 @@ async fn get_post_info(db: &sqlx::PgPool, id: i32) -> crate::Result<(PostInforma
     Ok((pi, tags, pool))
 }
 
+all_variants!(Rating { Safe, Questionable, Explicit });
+
+all_variants!(PostState { Processing, Ready, Failed });
+
+all_variants!(MediaType { Image, Video });
+
+all_variants!(PostVote { Like, Dislike });
+
+impl std::fmt::Display for PostState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PostState::Processing => "processing",
+            PostState::Ready => "ready",
+            PostState::Failed => "failed",
+        })
+    }
+}
+
+impl std::fmt::Display for Rating {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Rating::Safe => "safe",
+            Rating::Questionable => "questionable",
+            Rating::Explicit => "explicit",
+        })
+    }
+}
+
+impl std::fmt::Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MediaType::Image => "image",
+            MediaType::Video => "video",
+        })
+    }
+}
+
+impl std::fmt::Display for PostVote {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PostVote::Like => "like",
+            PostVote::Dislike => "dislike",
+        })
+    }
+}
+
 pub fn routes() -> Router<crate::State> {
     Router::new()
         .route("/posts", get(posts))
         .route("/posts/:id", get(post_page))
+        .route("/posts/random", get(random))
         .route("/posts/upload", get(upload))
 
         .route("/api/posts/upload", post(api_upload))
@@ -192,26 +301,93 @@ pub fn routes() -> Router<crate::State> {
 
 async fn posts(
     auth: Authentication,
+    settings: Settings,
     State(state): State<crate::State>,
     query: Query,
 ) -> crate::Result<impl IntoResponse> {
-    let results: Vec<QueriedPosts> = sqlx::query_as("
+    let page_size = settings.page_size as i64;
+    let blacklist = Blacklist::new(&settings.blacklist);
+    // Fetch one extra post to tell whether there's a next page
+    let mut results: Vec<QueriedPosts> = sqlx::query_as("
         SELECT ('/posts/' || id)                    AS url,
-               ('/static/thumb/' || thumbnail_path) AS thumbnail_path
+               thumbnail_url(state, thumbnail_path) AS thumbnail_path,
+               (thumbnail_dimensions(width, height, $7)).*
         FROM posts
-    ")  .fetch_all(&state.db)
+        WHERE rating <= $1
+        AND ($4 OR id NOT IN (SELECT blacklisted_posts($5, $6)))
+        ORDER BY id DESC
+        LIMIT $2
+        OFFSET $3
+    ")  .bind(settings.max_rating)
+        .bind(page_size + 1)
+        .bind(query.page as i64 * page_size)
+        .bind(query.reveal)
+        .bind(&blacklist.expressions)
+        .bind(&blacklist.tags)
+        .bind(state.config.data.thumbnails.resolution as i32)
+        .fetch_all(&state.db)
         .await?;
 
+    let has_next = results.len() as i64 > page_size;
+    results.truncate(page_size as usize);
+
+    let hidden_count: i64 = if blacklist.is_empty() {
+        0
+    } else {
+        sqlx::query_scalar("
+            SELECT COUNT(*)
+            FROM posts
+            WHERE rating <= $1
+            AND id IN (SELECT blacklisted_posts($2, $3))
+        ")  .bind(settings.max_rating)
+            .bind(&blacklist.expressions)
+            .bind(&blacklist.tags)
+            .fetch_one(&state.db)
+            .await?
+    };
+
     log::debug!("Serving query {query:?}");
 
     Ok(PostsTemplate {
         signed_in: auth.signed_in(),
+        settings,
         results,
+        page: query.page,
+        query: query.raw,
+        has_next,
+        reveal: query.reveal,
+        hidden_count,
     })
 }
 
+/// Redirects to a random post the user's settings would show them.
+async fn random(
+    settings: Settings,
+    State(state): State<crate::State>,
+    query: Query,
+) -> crate::Result<Redirect> {
+    let blacklist = Blacklist::new(&settings.blacklist);
+    let id: i32 = sqlx::query_scalar("
+        SELECT id
+        FROM posts
+        WHERE rating <= $1
+        AND ($2 OR id NOT IN (SELECT blacklisted_posts($3, $4)))
+        ORDER BY random()
+        LIMIT 1
+    ")  .bind(settings.max_rating)
+        .bind(query.reveal)
+        .bind(&blacklist.expressions)
+        .bind(&blacklist.tags)
+        .fetch_one(&state.db)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+
+    Ok(Redirect::to(&format!("/posts/{id}")))
+}
+
 async fn post_page(
     auth: Authentication,
+    settings: Settings,
     State(state): State<crate::State>,
     extract::Path(id): extract::Path<i32>,
 ) -> crate::Result<impl IntoResponse> {
@@ -225,6 +401,7 @@ async fn post_page(
 
     Ok(PostTemplate {
         signed_in: auth.signed_in(),
+        settings,
         post,
         tags,
         pools,
@@ -234,8 +411,48 @@ async fn post_page(
     })
 }
 
-async fn upload(auth: Authentication) -> impl IntoResponse {
-    UploadTemplate { signed_in: auth.signed_in(), }
+async fn upload(
+    auth: Authentication,
+    settings: Settings,
+    State(state): State<crate::State>,
+) -> crate::Result<impl IntoResponse> {
+    let uploads = &state.config.uploads;
+
+    Ok(UploadTemplate {
+        signed_in: auth.signed_in(),
+        settings,
+        max_files_per_request: uploads.max_files_per_request,
+        max_file_size: crate::readable_file_size(uploads.max_file_bytes())?,
+        quota: upload_quota(&auth.db, &auth).await?,
+    })
+}
+
+/// Returns what's left of the user's group's daily upload quota, counting
+/// posts uploaded in the last 24 hours
+async fn upload_quota(conn: impl sqlx::PgExecutor<'_>, auth: &Authentication) -> crate::Result<Quota> {
+    let (Some(user_id), Some(group_id)) = (auth.id, auth.group_id) else {
+        return Ok(Quota::default());
+    };
+
+    let (files_left, bytes_left): (Option<i64>, Option<i64>) = sqlx::query_as("
+        SELECT
+            groups.daily_upload_count - COUNT(posts.id),
+            (groups.daily_upload_bytes - COALESCE(SUM(posts.file_size), 0))::BIGINT
+        FROM groups
+        LEFT JOIN posts ON posts.uploader_id = $1
+            AND posts.uploaded_at > CURRENT_TIMESTAMP - INTERVAL '1 day'
+        WHERE groups.id = $2
+        GROUP BY groups.id;
+    ")  .bind(user_id)
+        .bind(group_id)
+        .fetch_one(conn)
+        .await?;
+
+    // Lowering a quota can leave some users over it
+    Ok(Quota {
+        files_left: files_left.map(|left| left.max(0)),
+        bytes_left: bytes_left.map(|left| left.max(0)),
+    })
 }
 
 async fn api_upload(
@@ -243,14 +460,26 @@ async fn api_upload(
     State(state): State<crate::State>,
     mut multipart: Multipart,
 ) -> crate::Result<Json<Vec<UploadResponse>>> {
-    if !user.has(Permission(Create, Posts)).await? {
+    if !user.has(Permission(Create, Uploads)).await? {
         return Err(crate::Error::Unauthorized);
     }
+    bans::check_restriction(&user, Restriction::Upload).await?;
 
+    let uploads = &state.config.uploads;
     let mut res = Vec::new();
     // For each file
     while let Some(field) = multipart.next_field().await? {
-        res.push(save_multipart_file(&user, &state, field).await?);
+        if res.len() >= uploads.max_files_per_request {
+            return Err(crate::Error::ContentTooLarge(format!(
+                "At most {} files can be uploaded at once", uploads.max_files_per_request)));
+        }
+
+        // Checked for every file, since the last one counts against it. It's
+        // checked again once the file's size is known.
+        let quota = upload_quota(&user.db, &user).await?;
+        quota.check_fits(0)?;
+
+        res.push(save_multipart_file(&user, &state, field, quota.size_limit(uploads)?).await?);
     }
 
     Ok(Json(res))
@@ -260,6 +489,7 @@ async fn save_multipart_file(
     user: &Authentication,
     state: &crate::State,
     mut field: Field<'_>,
+    limit: SizeLimit,
 ) -> crate::Result<UploadResponse> {
     // Determine whether the file type is supported
     let first_chunk = field.chunk().await?.ok_or_else(|| crate::Error::BadRequest("empty file".to_string()))?;
@@ -271,21 +501,26 @@ async fn save_multipart_file(
     }
     log::debug!("Inferred from {}b: {mime}", first_chunk.len());
 
-    // Write it to a temporary path while calculating its hash
-    let temp_path = std::env::temp_dir().join(temp_filename());
-    let mut temp_file = create_open(&temp_path).await?;
+    // Write it to a temporary path while calculating its hash, giving up as
+    // soon as it's too large
+    let temp = TempFile::at(state.config.data.temp().join(temp_filename()));
+    let mut temp_file = create_open(temp.path()).await?;
     let hash = {
         let mut hasher = md5::Md5::new();
-
-        hasher.update(&first_chunk);
-        temp_file.write_all(&first_chunk).await?;
-        while let Some(chunk) = field
-            .chunk()
-            .await?
-        {
-            hasher.update(&chunk);
-            temp_file.write_all(&chunk).await?;
+        let mut written = 0u64;
+
+        let mut chunk = Some(first_chunk);
+        while let Some(bytes) = chunk {
+            written += bytes.len() as u64;
+            if written > limit.bytes {
+                return Err(crate::Error::ContentTooLarge(limit.exceeded));
+            }
+
+            hasher.update(&bytes);
+            temp_file.write_all(&bytes).await?;
+            chunk = field.chunk().await?;
         }
+        temp_file.flush().await?;
 
         hasher.finalize().encode_hex::<String>()
     };
@@ -293,23 +528,36 @@ async fn save_multipart_file(
     let hash_path = hash_tree.join(&hash).with_extension(ext);
 
     // Create a database entry for it
-    let (w, h) = media_dimensions(&temp_path)?;
+    let (w, h) = state.media.dimensions(temp.path().to_path_buf()).await?;
     let media_type = match mime.matcher_type() {
         MatcherType::Image => MediaType::Image,
         MatcherType::Video => MediaType::Video,
         _ => unreachable!(),
     };
-    let file_size: i64 = fs::metadata(&temp_path)
+    let file_size: i64 = fs::metadata(temp.path())
         .await?
         .size()
         .try_into()
         .map_err(|_| crate::Error::ContentTooLarge(String::from("Files over 9,300 petabytes are not supported")))?;
     let thumb_hash_path = hash_tree.join(&hash).with_extension("webp");
-    let thumb_path = state.config.data.thumbnails().join(&thumb_hash_path);
+
+    // The post only exists once its media is in place. Until then, the row's
+    // lock on its md5 keeps duplicates from racing it there.
+    let mut tx = state.db.begin().await?;
+    // Concurrent uploads could each fit in what's left of the quota without
+    // the others, so they take turns from here and see each other's posts
+    if let Some(user_id) = user.id {
+        sqlx::query("
+            SELECT FROM users WHERE id = $1 FOR UPDATE;
+        ")  .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    upload_quota(&mut *tx, user).await?.check_fits(file_size)?;
 
     let post_id = sqlx::query_scalar("
-        INSERT INTO posts (uploader_id, md5, width, height, media_type, file_size, media_path, thumbnail_path)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        INSERT INTO posts (uploader_id, md5, width, height, media_type, file_size, media_path, thumbnail_path, state)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, 'processing')
         RETURNING id
     ")  .bind(user.id)
         .bind(&hash)
@@ -319,39 +567,22 @@ async fn save_multipart_file(
         .bind(file_size)
         .bind(hash_path.to_string_lossy())
         .bind(thumb_hash_path.to_string_lossy())
-        .fetch_one(&state.db)
-        .await;
-
-    // Move the media to its resting place, report the upload as a duplicate, or otherwise fail
-    match post_id {
-        Ok(post_id) => {
-            let resting_place = state.config.data.media().join(&hash_path);
-            fs::create_dir_all(resting_place.parent().unwrap()).await?;
-            fs::rename(temp_path, &resting_place).await?;
-        
-            create_thumbnail(&resting_place, &thumb_path, state.config.data.thumbnails.resolution).await?;
-            
-            Ok(UploadResponse { post_id, })
-        },
-        Err(sqlx::Error::Database(dbe)) if dbe.constraint() == Some("posts_md5_key") => {
-            fs::remove_file(&temp_path).await?;
-            Err(crate::Error::Conflict(String::from("Duplicate post")))
-        },
-        Err(e) => Err(e.into()),
-    }
-}
-
-/// TODO: It might be worth reusing the ictx/input/decoder from this function
-/// in create_thumbnail etc. Not sure of the performance implication of
-/// parsing the file twice for this.
-fn media_dimensions<P: AsRef<Path>>(path: P) -> crate::Result<(i32, i32)> {
-    let ictx = ffmpeg::format::input(&path)?;
-    let input = ictx.streams().best(ffmpeg::media::Type::Video)
-        .ok_or(crate::Error::BadRequest("Media has no streams".to_string()))?;
-    let context_decoder = CodecContext::from_parameters(input.parameters())?;
-    let decoder = context_decoder.decoder().video()?;
-
-    Ok((decoder.width() as i32, decoder.height() as i32))
+        .fetch_one(&mut *tx)
+        .await
+        .on_constraint("posts_md5_key", |_| crate::Error::Conflict(String::from("Duplicate post")))?;
+
+    // Move the media to its resting place, and leave thumbnailing it to the
+    // job queue
+    let resting_place = state.config.data.media().join(&hash_path);
+    fs::create_dir_all(resting_place.parent().unwrap()).await?;
+    let media = temp.rename(resting_place).await?;
+    jobs::enqueue(&mut tx, JobKind::Thumbnail, post_id).await?;
+
+    tx.commit().await?;
+    media.keep();
+    state.jobs.wake();
+
+    Ok(UploadResponse { post_id, })
 }
 
 // Create and open a file as read+write, creating its subdirectories if they
@@ -363,105 +594,79 @@ async fn create_open<P: AsRef<Path>>(path: P) -> crate::Result<File> {
     Ok(File::create_new(path).await?)
 }
 
-async fn create_thumbnail(src: &Path, dst: &Path, res: u32) -> crate::Result<()> {
-    let original_frame = first_frame(&src).context("Couldn't extract first frame of uploaded media")?;
-    let scaled_frame = scale_frame(original_frame, res)?;
-    write_frame(scaled_frame, dst).await.context("Couldn't write out computed frame")?;
-
-    Ok(())
-}
-
-/// Returns the first full frame from the input.
-fn first_frame(src: &Path) -> crate::Result<Video> {
-    let mut ictx = ffmpeg::format::input(&src)?;
-    let seek_sec = (ictx.duration() / 2).rescale((1, 1), ffmpeg::rescale::TIME_BASE);
-    ictx.seek(seek_sec, ..seek_sec)?;
-
-    let input = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .ok_or(crate::Error::BadRequest("Media has no streams".to_string()))?;
-    let video_stream_index = input.index();
-
-    let context_decoder = CodecContext::from_parameters(input.parameters())?;
-    let mut decoder = context_decoder.decoder().video()?;
-
-    // I'm not sure if there's an opportunity for a DOS by sending a large video
-    // that contains no full frames so I'll play it safe.
-    let mut frames_decoded = 0;
-    for (stream, packet) in ictx.packets() {
-        if stream.index() == video_stream_index {
-            let mut frame = Video::empty();
-            while decoder.receive_frame(&mut frame).is_err() {
-                decoder.send_packet(&packet)?;
-                if frames_decoded > 10 {
-                    return Err(crate::Error::UnsupportedMediaType(
-                        "Couldn't decode a thumbnail frame".to_string()
-                    ))
-                }
-
-                frames_decoded += 1;
-            };
+impl Quota {
+    /// Fails unless another file of `size` bytes fits in what's left
+    fn check_fits(&self, size: i64) -> crate::Result<()> {
+        if self.files_left == Some(0) || self.bytes_left == Some(0) {
+            return Err(crate::Error::ContentTooLarge(String::from(
+                "You've used up your daily upload quota")));
+        }
 
-            return Ok(frame);
+        match self.bytes_left {
+            Some(left) if size > left => Err(crate::Error::ContentTooLarge(format!(
+                "Only {} of your daily upload quota is left", crate::readable_file_size(left as u64)?))),
+            _ => Ok(()),
         }
     }
 
-    Err(crate::Error::UnsupportedMediaType("couldn't decode a frame".to_string()))
-}
-
-fn scale_frame(frame: Video, to: u32) -> crate::Result<Video> {
-    let (sw, sh) = (frame.width(), frame.height());
-    let ratio = (to as f32 / sw as f32).min(to as f32 / sh as f32);
-    let (w, h) = ((sw as f32 * ratio) as u32, (sh as f32 * ratio) as u32);
-
-    let mut scaler = ScalingContext::get(
-        frame.format(),
-        sw, sh,
-        ffmpeg::format::Pixel::YUV420P,
-        w, h,
-        ffmpeg::software::scaling::flag::Flags::BILINEAR,
-    )?;
-
-    let mut scaled_frame = Video::empty();
-    scaler.run(&frame, &mut scaled_frame)?;
-    scaled_frame.set_pts(Some(0));
+    /// Returns whichever is smaller of the largest allowed file and what's left
+    /// of the daily quota
+    fn size_limit(&self, uploads: &crate::config::Uploads) -> crate::Result<SizeLimit> {
+        let max_file_bytes = uploads.max_file_bytes();
+
+        Ok(match self.bytes_left {
+            Some(left) if (left as u64) < max_file_bytes => SizeLimit {
+                bytes: left as u64,
+                exceeded: format!("Only {} of your daily upload quota is left",
+                    crate::readable_file_size(left as u64)?),
+            },
+            _ => SizeLimit {
+                bytes: max_file_bytes,
+                exceeded: format!("Files can be at most {}", crate::readable_file_size(max_file_bytes)?),
+            },
+        })
+    }
 
-    Ok(scaled_frame)
+    pub fn readable_bytes_left(&self) -> Option<String> {
+        self.bytes_left.and_then(|left| crate::readable_file_size(left as u64).ok())
+    }
 }
 
-// TODO: use avif instead of webp
-async fn write_frame(frame: Video, dst: &Path) -> crate::Result<()> {
-    // HACK: Make sure the directory exists
-    fs::create_dir_all(dst.parent().expect("write_frame called with invalid dst")).await?;
-
-    let webp_encoder = codec::encoder::find(codec::id::Id::WEBP)
-        .expect("ffmpeg couldn't find thumbnail codec encoder");
-    let codec_ctx = CodecContext::new_with_codec(webp_encoder);
-    let mut encoder = codec_ctx.encoder().video()?;
+impl TempFile {
+    fn at(path: PathBuf) -> Self {
+        Self { path, kept: false }
+    }
 
-    encoder.set_height(frame.height());
-    encoder.set_width(frame.width());
-    encoder.set_format(frame.format());
-    encoder.set_time_base(ffmpeg::Rational::new(1, 1));
-    let mut opened_encoder = encoder.open()?;
+    fn path(&self) -> &Path {
+        &self.path
+    }
 
-    let mut output = ffmpeg::format::output(dst)?;
-    let mut output_stream = output.add_stream(webp_encoder)?;
-    output_stream.set_parameters(&opened_encoder);
+    /// Moves the file, returning a guard for its new path. `to` must be on the
+    /// same filesystem.
+    async fn rename(mut self, to: PathBuf) -> crate::Result<TempFile> {
+        fs::rename(&self.path, &to).await?;
+        self.kept = true;
 
-    output.write_header()?;
-    opened_encoder.send_frame(&frame).context("Couldn't send decoded frame to thumbnail encoder")?;
-    opened_encoder.send_eof()?;
+        Ok(TempFile::at(to))
+    }
 
-    let mut encoded = ffmpeg::Packet::empty();
-    while opened_encoder.receive_packet(&mut encoded).is_ok() {
-        encoded.write(&mut output)?;
+    fn keep(mut self) {
+        self.kept = true;
     }
+}
 
-    output.write_trailer()?;
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
 
-    Ok(())
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound =>
+                log::warn!("Couldn't remove {}: {e}", self.path.display()),
+            _ => (),
+        }
+    }
 }
 
 fn temp_filename() -> String {
@@ -469,3 +674,59 @@ fn temp_filename() -> String {
     rand_core::OsRng.fill_bytes(&mut out);
     hex::encode(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request, Response, StatusCode}};
+    use tower::ServiceExt;
+
+    use crate::testing::{app_with, post, session_token, CSRF};
+
+    #[sqlx::test]
+    async fn uploads_are_limited(db: sqlx::PgPool) {
+        let app = app_with(db.clone(), r#"
+            [uploads]
+            max-file-mib = 1
+        "#);
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let token = session_token(&response);
+        sqlx::query("
+            INSERT INTO permissions (group_id, operation, resource, own_only) SELECT id, 'create', 'uploads', false FROM groups WHERE name = 'users';
+        ").execute(&db).await.unwrap();
+
+        // A PNG signature padded out with zeroes
+        let upload = |size: usize| {
+            let mut body = Vec::new();
+            body.extend_from_slice(b"--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\r\n");
+            body.extend_from_slice(b"\x89PNG\r\n\x1a\n");
+            body.resize(body.len() + size, 0);
+            body.extend_from_slice(b"\r\n--X--\r\n");
+
+            let request = Request::post("/api/posts/upload")
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+                .header(header::COOKIE, format!("csrf={CSRF}; session={token}"))
+                .header("X-CSRF-Token", CSRF)
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let message = |response: Response<Body>| async {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8_lossy(&body).into_owned()
+        };
+
+        let response = upload(2 * 1024 * 1024).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(message(response).await.contains("at most 1.0"));
+
+        sqlx::query("UPDATE groups SET daily_upload_bytes = 1024 WHERE name = 'users';").execute(&db).await.unwrap();
+        let response = upload(2048).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(message(response).await.contains("daily upload quota"));
+
+        sqlx::query("UPDATE groups SET daily_upload_count = 0 WHERE name = 'users';").execute(&db).await.unwrap();
+        let response = upload(16).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(message(response).await.contains("used up"));
+    }
+}
@@ -5,14 +5,32 @@ use axum::{async_trait, extract::{self, FromRef, FromRequestParts}, http::reques
 struct RawParams {
     #[serde(default)]
     query: String,
+    #[serde(default)]
+    page: u32,
+    #[serde(default)]
+    reveal: bool,
 }
 
 #[derive(Default, Debug)]
 pub struct Query {
+    /// The query as the user typed it
+    pub raw: String,
+    /// Zero-indexed page of results
+    pub page: u32,
+    /// Whether to show posts hidden by the user's blacklist
+    pub reveal: bool,
     tags: Vec<String>,
     sort: Sort,
 }
 
+/// A user's blacklist flattened into parallel arrays for binding to the
+/// `blacklisted_posts` SQL function, as Postgres can't bind ragged arrays.
+#[derive(Default)]
+pub struct Blacklist {
+    pub expressions: Vec<i32>,
+    pub tags: Vec<String>,
+}
+
 #[derive(Default, Debug)]
 pub enum Sort {
     #[default]
@@ -36,8 +54,22 @@ where
                 .await
                 .context("parsing query string")?
                 .0;
-        let query_string = query_params.query.trim();
-        let mut result = Query::default();
+        let mut result = Query::parse(&query_params.query)?;
+        result.page = query_params.page;
+        result.reveal = query_params.reveal;
+
+        Ok(result)
+    }
+}
+
+impl Query {
+    /// Parses a query string such as `cat dog sort:score`.
+    pub fn parse(query_string: &str) -> crate::Result<Query> {
+        let query_string = query_string.trim();
+        let mut result = Query {
+            raw: query_string.to_string(),
+            ..Query::default()
+        };
 
         if query_string.is_empty() {
             return Ok(result);
@@ -50,7 +82,7 @@ where
                     "sort" => result.sort = Sort::try_from(rhs)?,
                     _ => continue,
                 }
-            } else {
+            } else if !part.is_empty() {
                 result.tags.push(part.to_string());
             }
         }
@@ -59,6 +91,31 @@ where
     }
 }
 
+impl Blacklist {
+    /// Builds a blacklist from query expressions. A post is blacklisted if it
+    /// has every tag of any one expression. Expressions that don't parse are
+    /// ignored.
+    pub fn new(expressions: &[String]) -> Self {
+        let mut blacklist = Self::default();
+        for (i, expression) in expressions.iter().enumerate() {
+            let Ok(query) = Query::parse(expression) else {
+                continue;
+            };
+
+            for tag in query.tags {
+                blacklist.expressions.push(i as i32);
+                blacklist.tags.push(tag);
+            }
+        }
+
+        blacklist
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+}
+
 impl TryFrom<&str> for Sort {
     type Error = crate::Error;
 
@@ -69,4 +126,72 @@ impl TryFrom<&str> for Sort {
             _ => Err(Self::Error::Query(format!("Invalid sort '{value}'"))),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request}};
+    use tower::ServiceExt;
+
+    use crate::testing::{app, post, session_token};
+
+    #[sqlx::test]
+    async fn blacklisted_posts_are_hidden_and_counted(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let session = session_token(&response);
+        // A post is hidden when it has every tag of any one expression
+        sqlx::query("
+            INSERT INTO user_settings (user_id, page_size, max_rating, blacklist, thumbnail_size, theme, layout)
+            SELECT id, 20, 'safe', '{\"cat dog\", bird}', 200, 'light', 'masonry' FROM users;
+        ").execute(&db).await.unwrap();
+
+        let posts: Vec<i32> = sqlx::query_scalar("
+            INSERT INTO posts (md5, width, height, media_type, file_size, media_path, thumbnail_path)
+            SELECT n::TEXT, 1, 1, 'image', 1, n || '.png', n || '.webp'
+            FROM generate_series(1, 4) AS n
+            ORDER BY n
+            RETURNING id;
+        ").fetch_all(&db).await.unwrap();
+        sqlx::query("
+            WITH category AS (
+                INSERT INTO tag_categories (name, colour) VALUES ('general', '#000000') RETURNING id
+            )
+            INSERT INTO tags (name, category)
+            SELECT name, id FROM category, UNNEST(ARRAY['cat', 'dog', 'bird']) AS name;
+        ").execute(&db).await.unwrap();
+        sqlx::query("
+            INSERT INTO post_tags (post_id, tag_id)
+            SELECT post_id, tags.id
+            FROM UNNEST($1::INTEGER[], $2::TEXT[]) AS t(post_id, tag)
+            JOIN tags ON tags.name = t.tag;
+        ")  .bind([posts[0], posts[1], posts[1], posts[2]])
+            .bind(["cat", "cat", "dog", "bird"])
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let listing = |uri: &'static str| {
+            let request = Request::get(uri)
+                .header(header::COOKIE, format!("session={session}"))
+                .body(Body::empty())
+                .unwrap();
+            async {
+                let response = app.clone().oneshot(request).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                String::from_utf8_lossy(&body).into_owned()
+            }
+        };
+        let shows = |page: &str, post: i32| page.contains(&format!("href=\"/posts/{post}\""));
+
+        let page = listing("/posts").await;
+        assert!(page.contains("2 posts hidden by your blacklist"));
+        assert!(shows(&page, posts[0]));
+        assert!(!shows(&page, posts[1]));
+        assert!(!shows(&page, posts[2]));
+        assert!(shows(&page, posts[3]));
+
+        let page = listing("/posts?reveal=true").await;
+        assert!(page.contains("Showing 2 blacklisted posts"));
+        assert!(posts.iter().all(|&post| shows(&page, post)));
+    }
+}
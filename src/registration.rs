@@ -0,0 +1,319 @@
+//! Invite codes and the queue of accounts awaiting approval, for when
+//! registration isn't open to everyone. See [`crate::config::Registration`].
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use askama_axum::IntoResponse;
+use axum::{extract::{self, State}, response::Redirect, routing::{get, post}, Form, Router};
+use uuid::Uuid;
+
+use crate::{
+    config::Registration,
+    error::ResultExt,
+    extractors::{Authentication, Settings},
+    groups::require_superuser,
+};
+
+/// Longest an invite can be made to last, in days
+const MAX_INVITE_DAYS: i32 = 365;
+
+#[derive(askama_axum::Template)]
+#[template(path = "registration.html")]
+struct RegistrationTemplate {
+    signed_in: bool,
+    settings: Settings,
+    mode: Registration,
+    default_group: String,
+    invites: Vec<Invite>,
+    pending: Vec<PendingAccount>,
+    /// Groups an invite can be for, as (id, name)
+    groups: Vec<(i32, String)>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Invite {
+    pub id: i32,
+    pub code: String,
+    pub group_name: String,
+    pub group_colour: String,
+    pub created_at: time::OffsetDateTime,
+    pub expires_at: time::OffsetDateTime,
+    pub used_at: Option<time::OffsetDateTime>,
+    pub used_by: Option<Uuid>,
+    pub used_by_name: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingAccount {
+    pub id: Uuid,
+    pub username: String,
+    pub created_at: time::OffsetDateTime,
+}
+
+#[derive(serde::Deserialize)]
+struct InviteForm {
+    group_id: i32,
+    days: i32,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/registration", get(registration_page))
+
+        .route("/api/registration/invites", post(create_invite))
+        .route("/api/registration/invites/:id/revoke", post(revoke_invite))
+        .route("/api/registration/pending/:id/approve", post(approve_account))
+        .route("/api/registration/pending/:id/reject", post(reject_account))
+}
+
+/// Returns the group new accounts go in, unless an invite says otherwise
+pub async fn default_group(conn: impl sqlx::PgExecutor<'_>, name: &str) -> crate::Result<i32> {
+    sqlx::query_scalar("
+        SELECT id FROM groups WHERE name = $1;
+    ")  .bind(name)
+        .fetch_one(conn)
+        .await
+        .on_no_rows(crate::Error::Internal(anyhow::anyhow!("Default group '{name}' doesn't exist")))
+}
+
+/// Locks an unused, unexpired invite, returning its ID and group. It's only
+/// used up by [`use_invite`], in the same transaction.
+pub async fn claim_invite(conn: &mut sqlx::PgConnection, code: &str) -> crate::Result<(i32, i32)> {
+    sqlx::query_as("
+        SELECT id, group_id
+        FROM invites
+        WHERE code = $1
+        AND used_at IS NULL
+        AND expires_at > CURRENT_TIMESTAMP
+        FOR UPDATE;
+    ")  .bind(code)
+        .fetch_one(conn)
+        .await
+        .on_no_rows(crate::Error::BadRequest(String::from("Invalid or expired invite code")))
+}
+
+pub async fn use_invite(conn: &mut sqlx::PgConnection, invite_id: i32, user_id: Uuid) -> crate::Result<()> {
+    sqlx::query("
+        UPDATE invites SET used_by = $2, used_at = CURRENT_TIMESTAMP WHERE id = $1;
+    ")  .bind(invite_id)
+        .bind(user_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+async fn registration_page(
+    auth: Authentication,
+    settings: Settings,
+    State(state): State<crate::State>,
+) -> crate::Result<impl IntoResponse> {
+    require_superuser(&auth).await?;
+
+    let invites: Vec<Invite> = sqlx::query_as("
+        SELECT
+            invites.id,
+            invites.code,
+            groups.name AS group_name,
+            groups.colour AS group_colour,
+            invites.created_at,
+            invites.expires_at,
+            invites.used_at,
+            invites.used_by,
+            users.username AS used_by_name
+        FROM invites
+        JOIN groups ON groups.id = invites.group_id
+        LEFT JOIN users ON users.id = invites.used_by
+        ORDER BY invites.created_at DESC;
+    ")  .fetch_all(&auth.db)
+        .await?;
+
+    let pending: Vec<PendingAccount> = sqlx::query_as("
+        SELECT id, username, created_at
+        FROM users
+        WHERE NOT approved
+        ORDER BY created_at;
+    ")  .fetch_all(&auth.db)
+        .await?;
+
+    let groups: Vec<(i32, String)> = sqlx::query_as("
+        SELECT id, name FROM groups ORDER BY superuser, name;
+    ")  .fetch_all(&auth.db)
+        .await?;
+
+    Ok(RegistrationTemplate {
+        signed_in: true,
+        settings,
+        mode: state.config.accounts.registration,
+        default_group: state.config.accounts.default_group.clone(),
+        invites,
+        pending,
+        groups,
+    })
+}
+
+async fn create_invite(
+    auth: Authentication,
+    Form(form): Form<InviteForm>,
+) -> crate::Result<Redirect> {
+    let actor_id = require_superuser(&auth).await?;
+
+    if !(1..=MAX_INVITE_DAYS).contains(&form.days) {
+        return Err(crate::Error::BadRequest(format!(
+            "Invites must last between 1 and {MAX_INVITE_DAYS} days")));
+    }
+
+    let mut secret = [0u8; 12];
+    OsRng.fill_bytes(&mut secret);
+
+    sqlx::query("
+        INSERT INTO invites (code, group_id, created_by, expires_at)
+        VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(days => $4));
+    ")  .bind(hex::encode(secret))
+        .bind(form.group_id)
+        .bind(actor_id)
+        .bind(form.days)
+        .execute(&auth.db)
+        .await
+        .on_constraint("invites_group_id_fkey", |_| crate::Error::BadRequest(String::from("No such group")))?;
+
+    Ok(Redirect::to("/registration"))
+}
+
+/// Deletes an invite that hasn't been used yet
+async fn revoke_invite(
+    auth: Authentication,
+    extract::Path(id): extract::Path<i32>,
+) -> crate::Result<Redirect> {
+    require_superuser(&auth).await?;
+
+    let revoked = sqlx::query("
+        DELETE FROM invites WHERE id = $1 AND used_at IS NULL;
+    ")  .bind(id)
+        .execute(&auth.db)
+        .await?;
+    if revoked.rows_affected() == 0 {
+        return Err(crate::Error::NotFound);
+    }
+
+    Ok(Redirect::to("/registration"))
+}
+
+async fn approve_account(
+    auth: Authentication,
+    extract::Path(id): extract::Path<Uuid>,
+) -> crate::Result<Redirect> {
+    require_superuser(&auth).await?;
+
+    let approved = sqlx::query("
+        UPDATE users SET approved = true WHERE id = $1 AND NOT approved;
+    ")  .bind(id)
+        .execute(&auth.db)
+        .await?;
+    if approved.rows_affected() == 0 {
+        return Err(crate::Error::NotFound);
+    }
+
+    Ok(Redirect::to("/registration"))
+}
+
+/// Deletes an account that was never approved, freeing its username
+async fn reject_account(
+    auth: Authentication,
+    extract::Path(id): extract::Path<Uuid>,
+) -> crate::Result<Redirect> {
+    require_superuser(&auth).await?;
+
+    let rejected = sqlx::query("
+        DELETE FROM users WHERE id = $1 AND NOT approved;
+    ")  .bind(id)
+        .execute(&auth.db)
+        .await?;
+    if rejected.rows_affected() == 0 {
+        return Err(crate::Error::NotFound);
+    }
+
+    Ok(Redirect::to("/registration"))
+}
+
+impl Invite {
+    fn expired(&self) -> bool {
+        self.expires_at <= time::OffsetDateTime::now_utc()
+    }
+
+    fn expires_at_rfc2822(&self) -> String {
+        self.expires_at
+            .format(&time::format_description::well_known::Rfc2822)
+            .expect("Couldn't convert invite timestamp to RFC2822 string")
+    }
+
+    fn created_at_rfc2822(&self) -> String {
+        self.created_at
+            .format(&time::format_description::well_known::Rfc2822)
+            .expect("Couldn't convert invite timestamp to RFC2822 string")
+    }
+}
+
+impl PendingAccount {
+    fn created_ago(&self) -> String {
+        timeago::Formatter::new().convert((time::OffsetDateTime::now_utc() - self.created_at).unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use crate::testing::{app_with, post, session_cookie};
+
+    #[sqlx::test]
+    async fn register_refused_when_closed(db: sqlx::PgPool) {
+        let app = app_with(db, r#"registration = "closed""#);
+
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn invite_registration_uses_invite_group(db: sqlx::PgPool) {
+        let app = app_with(db.clone(), r#"registration = "invite""#);
+        sqlx::Executor::execute(&db, "
+            INSERT INTO groups (name) VALUES ('invited');
+            INSERT INTO invites (code, group_id, expires_at)
+            SELECT 'valid', id, CURRENT_TIMESTAMP + INTERVAL '1 day' FROM groups WHERE name = 'invited';
+            INSERT INTO invites (code, group_id, expires_at)
+            SELECT 'expired', id, CURRENT_TIMESTAMP - INTERVAL '1 day' FROM groups WHERE name = 'invited';
+        ").await.unwrap();
+
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2&invite=expired").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2&invite=valid").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let group: String = sqlx::query_scalar("
+            SELECT groups.name FROM users JOIN groups ON groups.id = users.group_id WHERE username = 'alice';
+        ").fetch_one(&db).await.unwrap();
+        assert_eq!(group, "invited");
+
+        // Invites are single-use
+        let response = post(&app, "/api/auth/register", None, "username=bob&password=hunter2&invite=valid").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn approval_registration_waits_for_approval(db: sqlx::PgPool) {
+        let app = app_with(db.clone(), r#"registration = "approval""#);
+
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(session_cookie(&response).is_none());
+
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        sqlx::query("UPDATE users SET approved = true;").execute(&db).await.unwrap();
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Display;
+use sqlx::{Postgres, Row, TypeInfo};
+
+use crate::{
+    bans::Restriction,
+    extractors::{Layout, Operation, Resource, Theme},
+    groups::AuditAction,
+    jobs::JobKind,
+    posts::{MediaType, PostState, PostVote, Rating},
+    throttle::ThrottleKind,
+};
+
+/// Defines `ALL` on an enum stored as a Postgres enum, listing its variants in
+/// the given order for [`check`] and for anything offering them as choices.
+/// Leaving a variant out is a compile error, as the list is also matched on.
+macro_rules! all_variants {
+    ($enum:ident { $($variant:ident),+ $(,)? }) => {
+        impl $enum {
+            pub const ALL: [$enum; [$(stringify!($variant)),+].len()] = [$($enum::$variant),+];
+        }
+
+        const _: () = {
+            #[allow(dead_code)]
+            fn listed(variant: $enum) {
+                match variant {
+                    $($enum::$variant => ()),+
+                }
+            }
+        };
+    };
+}
+pub(crate) use all_variants;
+
+/// Checks that Rust enums stored as Postgres enums agree with the database on
+/// their variants. A mismatch would otherwise only show up as an error the
+/// first time the missing variant is used.
+pub async fn check(db: &sqlx::PgPool) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    problems.extend(check_enum(db, &Operation::ALL).await?);
+    problems.extend(check_enum(db, &Resource::ALL).await?);
+    problems.extend(check_enum(db, &Rating::ALL).await?);
+    problems.extend(check_enum(db, &MediaType::ALL).await?);
+    problems.extend(check_enum(db, &PostVote::ALL).await?);
+    problems.extend(check_enum(db, &Theme::ALL).await?);
+    problems.extend(check_enum(db, &Layout::ALL).await?);
+    problems.extend(check_enum(db, &AuditAction::ALL).await?);
+    problems.extend(check_enum(db, &ThrottleKind::ALL).await?);
+    problems.extend(check_enum(db, &Restriction::ALL).await?);
+    problems.extend(check_enum(db, &PostState::ALL).await?);
+    problems.extend(check_enum(db, &JobKind::ALL).await?);
+
+    if !problems.is_empty() {
+        anyhow::bail!("Database enums don't match the code:\n{}", problems.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Returns a description of each variant that exists on only one side.
+async fn check_enum<T>(db: &sqlx::PgPool, variants: &[T]) -> anyhow::Result<Vec<String>>
+where
+    T: for<'q> sqlx::Encode<'q, Postgres> + for<'r> sqlx::Decode<'r, Postgres> + sqlx::Type<Postgres>,
+    T: Display + Copy + Send + Sync,
+{
+    let type_name = T::type_info().name().to_string();
+    let mut problems = Vec::new();
+
+    for &variant in variants {
+        // Naming the type keeps statements for different enums from sharing a
+        // cached parameter type
+        let encoded = sqlx::query(&format!("SELECT $1::{type_name}::TEXT;"))
+            .bind(variant)
+            .execute(db)
+            .await;
+
+        if encoded.is_err() {
+            problems.push(format!("'{variant}' is missing from Postgres enum {type_name}"));
+        }
+    }
+
+    let labels = sqlx::query(&format!("
+        SELECT label::TEXT, label
+        FROM unnest(enum_range(NULL::{type_name})) AS label;
+    ")) .fetch_all(db)
+        .await?;
+
+    for label in labels {
+        if label.try_get::<T, _>(1).is_err() {
+            let name: String = label.get(0);
+            problems.push(format!("'{name}' in Postgres enum {type_name} has no Rust variant"));
+        }
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    #[sqlx::test]
+    async fn enums_match_migrations(db: sqlx::PgPool) {
+        super::check(&db).await.unwrap();
+    }
+}
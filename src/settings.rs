@@ -0,0 +1,114 @@
+use askama_axum::IntoResponse;
+use axum::{response::Redirect, routing::{get, post}, Form, Router};
+use axum_extra::extract::{cookie::{Cookie, SameSite}, CookieJar};
+
+use crate::{
+    extractors::{Authentication, Layout, Settings, Theme, MAX_BLACKLIST_LENGTH, PAGE_SIZES, THUMBNAIL_SIZES},
+    posts::Rating,
+};
+
+#[derive(askama_axum::Template)]
+#[template(path = "settings.html")]
+struct SettingsTemplate {
+    signed_in: bool,
+    settings: Settings,
+    superuser: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct SettingsForm {
+    page_size: i32,
+    max_rating: Rating,
+    /// One query expression per line
+    blacklist: String,
+    thumbnail_size: i32,
+    theme: Theme,
+    layout: Layout,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/settings", get(settings_page))
+
+        .route("/api/settings", post(save_settings))
+}
+
+async fn settings_page(
+    auth: Authentication,
+    settings: Settings,
+) -> crate::Result<impl IntoResponse> {
+    Ok(SettingsTemplate {
+        signed_in: auth.signed_in(),
+        settings,
+        superuser: auth.is_superuser().await?,
+    })
+}
+
+/// Saves settings to the database for signed-in users, or to a cookie for
+/// anonymous users.
+async fn save_settings(
+    auth: Authentication,
+    jar: CookieJar,
+    Form(form): Form<SettingsForm>,
+) -> crate::Result<(CookieJar, Redirect)> {
+    if !PAGE_SIZES.contains(&form.page_size) {
+        return Err(crate::Error::BadRequest(format!(
+            "Page size must be between {} and {}", PAGE_SIZES.start(), PAGE_SIZES.end())));
+    }
+
+    if !THUMBNAIL_SIZES.contains(&form.thumbnail_size) {
+        return Err(crate::Error::BadRequest(format!(
+            "Thumbnail size must be between {} and {}", THUMBNAIL_SIZES.start(), THUMBNAIL_SIZES.end())));
+    }
+
+    let blacklist: Vec<String> = form.blacklist.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+    if blacklist.len() > MAX_BLACKLIST_LENGTH {
+        return Err(crate::Error::BadRequest(format!(
+            "Blacklist may have at most {MAX_BLACKLIST_LENGTH} entries")));
+    }
+
+    let settings = Settings {
+        page_size: form.page_size,
+        max_rating: form.max_rating,
+        blacklist,
+        thumbnail_size: form.thumbnail_size,
+        theme: form.theme,
+        layout: form.layout,
+    };
+
+    let Some(user_id) = auth.id else {
+        let cookie = Cookie::build(("settings", serde_json::to_string(&settings).map_err(anyhow::Error::from)?))
+            .same_site(SameSite::Strict)
+            .path("/")
+            .permanent()
+            .build();
+
+        return Ok((jar.add(cookie), Redirect::to("/settings")));
+    };
+
+    sqlx::query("
+        INSERT INTO user_settings (user_id, page_size, max_rating, blacklist, thumbnail_size, theme, layout)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (user_id) DO UPDATE SET
+            page_size = EXCLUDED.page_size,
+            max_rating = EXCLUDED.max_rating,
+            blacklist = EXCLUDED.blacklist,
+            thumbnail_size = EXCLUDED.thumbnail_size,
+            theme = EXCLUDED.theme,
+            layout = EXCLUDED.layout;
+    ")  .bind(user_id)
+        .bind(settings.page_size)
+        .bind(settings.max_rating)
+        .bind(&settings.blacklist)
+        .bind(settings.thumbnail_size)
+        .bind(settings.theme)
+        .bind(settings.layout)
+        .execute(&auth.db)
+        .await?;
+
+    Ok((jar, Redirect::to("/settings")))
+}
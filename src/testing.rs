@@ -0,0 +1,84 @@
+//! Fixtures for tests that run the app against a real database: set
+//! `DATABASE_URL` to a Postgres server that sqlx may create throwaway test
+//! databases on.
+
+use std::{sync::Arc, time::Duration};
+use axum::{body::Body, http::{header, Request, Response}};
+use tower::ServiceExt;
+
+const CONFIG: &str = r#"
+    [network]
+    bind = "127.0.0.1:0"
+    database = ""
+
+    [data]
+    media = "/nonexistent"
+    temp = "/tmp"
+
+    [data.thumbnails]
+    resolution = 350
+
+    [accounts]
+    username-regex = '^\w[\w ]{0,30}\w$'
+    password-regex = '^.{1,128}$'
+    initial-superuser-password = "changeme"
+"#;
+
+pub(crate) fn app(db: sqlx::PgPool) -> axum::Router {
+    app_with(db, "")
+}
+
+/// Builds the app with extra `[accounts]` settings, or further sections
+/// after them
+pub(crate) fn app_with(db: sqlx::PgPool, accounts: &str) -> axum::Router {
+    crate::app(state_with(db, accounts))
+}
+
+pub(crate) fn state_with(db: sqlx::PgPool, accounts: &str) -> crate::State {
+    let config: Arc<crate::config::Config> = Arc::new(toml::from_str(&format!("{CONFIG}{accounts}")).unwrap());
+    crate::State {
+        config: Arc::clone(&config),
+        db,
+        auth_cache: Arc::new(crate::cache::AuthCache::new(Duration::from_secs(60))),
+        hasher: Arc::new(crate::password::Hasher::new(&config.accounts).unwrap()),
+        oidc: Arc::new(crate::oidc::Provider::new()),
+        media: Arc::new(crate::media::Workers::new(&config.uploads)),
+        jobs: Arc::new(crate::jobs::Queue::new()),
+    }
+}
+
+/// A CSRF token, sent as both the cookie and its copy
+pub(crate) const CSRF: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+pub(crate) async fn post(app: &axum::Router, uri: &str, session: Option<&str>, body: &str) -> Response<Body> {
+    let cookie = match session {
+        Some(session) => format!("csrf={CSRF}; session={session}"),
+        None => format!("csrf={CSRF}"),
+    };
+    let request = Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(header::COOKIE, cookie)
+        .header("X-CSRF-Token", CSRF);
+
+    app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()).await.unwrap()
+}
+
+/// Returns the `Set-Cookie` header for the session cookie, if any
+pub(crate) fn session_cookie(response: &Response<Body>) -> Option<String> {
+    response.headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find(|v| v.starts_with("session="))
+        .map(str::to_string)
+}
+
+/// Returns the session token set by a response
+pub(crate) fn session_token(response: &Response<Body>) -> String {
+    let cookie = session_cookie(response).expect("No session cookie was set");
+    cookie["session=".len()..].split(';').next().unwrap().to_string()
+}
+
+pub(crate) async fn session_count(db: &sqlx::PgPool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM sessions;").fetch_one(db).await.unwrap()
+}
@@ -0,0 +1,225 @@
+//! Sign-in throttling. Each failed sign-in counts against both the client's IP
+//! address and the username tried. After a few free failures, either one is
+//! locked out for a period that doubles with each further failure.
+
+use crate::{config::Accounts, extractors::Client, schema::all_variants};
+
+#[derive(Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "THROTTLE_KIND", rename_all = "lowercase")]
+pub enum ThrottleKind {
+    Ip,
+    Username,
+}
+
+/// The throttles a sign-in attempt counts against
+pub struct Throttles {
+    keys: Vec<(ThrottleKind, String)>,
+    /// Those this attempt locked out when reserving it, which are unlocked if
+    /// it's released
+    locked: Vec<(ThrottleKind, String)>,
+}
+
+impl Throttles {
+    pub fn new(client: &Client, username: &str) -> Self {
+        let mut keys = vec![(ThrottleKind::Username, username.to_string())];
+        if let Some(ip) = client.ip {
+            keys.push((ThrottleKind::Ip, ip.to_string()));
+        }
+
+        Self { keys, locked: Vec::new() }
+    }
+
+    /// Counts an attempt as failed before it's checked, so attempts made at
+    /// once can't all be checked before any of them has failed. Fails with
+    /// [`crate::Error::TooManyRequests`] if any throttle is locked out. The
+    /// attempt using up the free failures locks the throttle itself, and is
+    /// still checked. Failures are forgotten after the longest lockout.
+    pub async fn reserve(&mut self, db: &sqlx::PgPool, accounts: &Accounts) -> crate::Result<()> {
+        for (kind, key) in &self.keys {
+            // The row stays locked until the lockout's set, so attempts made
+            // at once wait for it rather than slipping in before it. Locked
+            // throttles are left alone.
+            let mut tx = db.begin().await?;
+            let failures: Option<i32> = sqlx::query_scalar("
+                INSERT INTO login_throttles (kind, key, failures)
+                VALUES ($1, $2, 1)
+                ON CONFLICT (kind, key) DO UPDATE SET
+                    failures = CASE
+                        WHEN login_throttles.last_failure_at < CURRENT_TIMESTAMP - make_interval(secs => $3)
+                        THEN 1
+                        ELSE login_throttles.failures + 1
+                    END,
+                    last_failure_at = CURRENT_TIMESTAMP
+                WHERE login_throttles.locked_until IS NULL
+                OR login_throttles.locked_until <= CURRENT_TIMESTAMP
+                RETURNING failures;
+            ")  .bind(kind)
+                .bind(key)
+                .bind(accounts.login_lockout_max_seconds as f64)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+            let Some(failures) = failures else {
+                let seconds: Option<i64> = sqlx::query_scalar("
+                    SELECT CEIL(EXTRACT(EPOCH FROM locked_until - CURRENT_TIMESTAMP))::BIGINT
+                    FROM login_throttles
+                    WHERE kind = $1
+                    AND key = $2;
+                ")  .bind(kind)
+                    .bind(key)
+                    .fetch_optional(&mut *tx)
+                    .await?
+                    .flatten();
+
+                return Err(crate::Error::TooManyRequests(seconds.unwrap_or(1).max(1) as u64));
+            };
+
+            let Some(excess) = (failures as u32).checked_sub(accounts.login_free_attempts + 1) else {
+                tx.commit().await?;
+                continue;
+            };
+            // Doubles with each failure past the free ones
+            let lockout = accounts.login_lockout_seconds
+                .saturating_mul(1 << excess.min(32))
+                .min(accounts.login_lockout_max_seconds);
+
+            sqlx::query("
+                UPDATE login_throttles
+                SET locked_until = CURRENT_TIMESTAMP + make_interval(secs => $3)
+                WHERE kind = $1
+                AND key = $2;
+            ")  .bind(kind)
+                .bind(key)
+                .bind(lockout as f64)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            self.locked.push((*kind, key.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Takes back a reserved attempt that turned out to be right, without
+    /// forgiving earlier failures, e.g. when a password's right but a second
+    /// factor is still needed.
+    pub async fn release(&self, db: &sqlx::PgPool) -> crate::Result<()> {
+        for throttle in &self.keys {
+            self.release_one(db, throttle).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets failures against the username after a successful sign-in. The
+    /// IP address only has the attempt taken back, so signing in to one
+    /// account doesn't reset guessing at others.
+    pub async fn succeed(&self, db: &sqlx::PgPool) -> crate::Result<()> {
+        for throttle @ (kind, key) in &self.keys {
+            match kind {
+                ThrottleKind::Username => {
+                    sqlx::query("
+                        DELETE FROM login_throttles WHERE kind = $1 AND key = $2;
+                    ")  .bind(kind)
+                        .bind(key)
+                        .execute(db)
+                        .await?;
+                },
+                ThrottleKind::Ip => self.release_one(db, throttle).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn release_one(&self, db: &sqlx::PgPool, (kind, key): &(ThrottleKind, String)) -> crate::Result<()> {
+        sqlx::query("
+            UPDATE login_throttles
+            SET failures = failures - 1,
+                locked_until = CASE WHEN $3 THEN NULL ELSE locked_until END
+            WHERE kind = $1
+            AND key = $2
+            AND failures > 0;
+        ")  .bind(kind)
+            .bind(key)
+            .bind(self.locked.iter().any(|(k, v)| k == kind && v == key))
+            .execute(db)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Deletes throttles that are no longer locked out and whose failures would be
+/// forgotten anyway.
+pub async fn purge(db: &sqlx::PgPool, accounts: &Accounts) -> crate::Result<u64> {
+    Ok(sqlx::query("
+        DELETE FROM login_throttles
+        WHERE last_failure_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+        AND (locked_until IS NULL OR locked_until < CURRENT_TIMESTAMP);
+    ")  .bind(accounts.login_lockout_max_seconds as f64)
+        .execute(db)
+        .await?
+        .rows_affected())
+}
+
+all_variants!(ThrottleKind { Ip, Username });
+
+impl std::fmt::Display for ThrottleKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ThrottleKind::Ip => "ip",
+            ThrottleKind::Username => "username",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+
+    use crate::testing::{app, post};
+
+    #[sqlx::test]
+    async fn login_locks_out_after_failures(db: sqlx::PgPool) {
+        let app = app(db);
+        post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        post(&app, "/api/auth/register", None, "username=bob&password=hunter2").await;
+
+        // 5 free failures, then the 6th starts a lockout
+        for _ in 0..6 {
+            let response = post(&app, "/api/auth/login", None, "username=alice&password=wrong").await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+
+        let response = post(&app, "/api/auth/login", None, "username=bob&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    #[sqlx::test]
+    async fn concurrent_failures_are_still_locked_out(db: sqlx::PgPool) {
+        let app = app(db);
+        post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+
+        let guesses: Vec<_> = (0..20)
+            .map(|_| {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    post(&app, "/api/auth/login", None, "username=alice&password=wrong").await.status()
+                })
+            })
+            .collect();
+        let mut checked = 0;
+        for guess in guesses {
+            match guess.await.unwrap() {
+                StatusCode::UNAUTHORIZED => checked += 1,
+                status => assert_eq!(status, StatusCode::TOO_MANY_REQUESTS),
+            }
+        }
+        // The 5 free failures and the one starting the lockout
+        assert_eq!(checked, 6);
+    }
+}
@@ -0,0 +1,248 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use askama_axum::IntoResponse;
+use axum::{extract, response::Redirect, routing::{get, post}, Form, Router};
+use sha2::Digest;
+use uuid::Uuid;
+
+use crate::extractors::{Authentication, Operation, Permission, Resource, Settings};
+
+/// Longest allowed token name, in characters
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(askama_axum::Template)]
+#[template(path = "tokens.html")]
+struct TokensTemplate {
+    signed_in: bool,
+    settings: Settings,
+    tokens: Vec<ApiToken>,
+    /// Every scope a token can be given, by resource
+    scopes: Vec<(Resource, Vec<Permission>)>,
+    /// A token that was just created. This is the only time it's shown.
+    created: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub created_at: time::OffsetDateTime,
+    pub last_used_at: Option<time::OffsetDateTime>,
+    pub last_used_ip: Option<String>,
+    /* Additional information */
+    pub scopes: Vec<String>,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/auth/tokens", get(tokens_page))
+
+        .route("/api/auth/tokens", post(create_token))
+        .route("/api/auth/tokens/:id/revoke", post(revoke_token))
+}
+
+/// Hashes an API token for storage. Tokens are long and random, so a fast
+/// hash is enough to keep them useless if the database leaks.
+pub fn hash(token: &str) -> String {
+    hex::encode(sha2::Sha256::digest(token.as_bytes()))
+}
+
+/// Returns the signed-in user, refusing requests made with an API token so a
+/// leaked token can't be used to mint more.
+pub fn session_user(auth: &Authentication) -> crate::Result<Uuid> {
+    match (auth.id, auth.token_id) {
+        (Some(id), None) => Ok(id),
+        _ => Err(crate::Error::Unauthorized),
+    }
+}
+
+async fn render(
+    auth: &Authentication,
+    settings: Settings,
+    user_id: Uuid,
+    created: Option<String>,
+) -> crate::Result<TokensTemplate> {
+    let tokens: Vec<ApiToken> = sqlx::query_as("
+        SELECT id, name, created_at, last_used_at, last_used_ip,
+            ARRAY(
+                SELECT operation::TEXT || ':' || resource::TEXT
+                FROM api_token_scopes
+                WHERE token_id = api_tokens.id
+                ORDER BY resource, operation
+            ) AS scopes
+        FROM api_tokens
+        WHERE user_id = $1
+        ORDER BY created_at DESC;
+    ")  .bind(user_id)
+        .fetch_all(&auth.db)
+        .await?;
+
+    Ok(TokensTemplate {
+        signed_in: true,
+        settings,
+        tokens,
+        scopes: Resource::ALL.iter()
+            .map(|&r| (r, Operation::ALL.iter().map(|&o| Permission(o, r)).collect()))
+            .collect(),
+        created,
+    })
+}
+
+async fn tokens_page(
+    auth: Authentication,
+    settings: Settings,
+) -> crate::Result<impl IntoResponse> {
+    let user_id = session_user(&auth)?;
+
+    render(&auth, settings, user_id, None).await
+}
+
+/// Creates a token from a form with a `name` and any number of `scope` fields.
+async fn create_token(
+    auth: Authentication,
+    settings: Settings,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> crate::Result<impl IntoResponse> {
+    let user_id = session_user(&auth)?;
+
+    let name = fields.iter()
+        .find(|(k, _)| k == "name")
+        .map(|(_, v)| v.trim())
+        .unwrap_or_default();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(crate::Error::BadRequest(format!(
+            "Token name must be between 1 and {MAX_NAME_LENGTH} characters")));
+    }
+
+    let scopes = fields.iter()
+        .filter(|(k, _)| k == "scope")
+        .map(|(_, v)| v.parse())
+        .collect::<crate::Result<Vec<Permission>>>()?;
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let token = hex::encode(secret);
+
+    let mut tx = auth.db.begin().await?;
+    let token_id: i32 = sqlx::query_scalar("
+        INSERT INTO api_tokens (user_id, name, hash)
+        VALUES ($1, $2, $3)
+        RETURNING id;
+    ")  .bind(user_id)
+        .bind(name)
+        .bind(hash(&token))
+        .fetch_one(&mut *tx)
+        .await?;
+
+    for Permission(operation, resource) in scopes {
+        sqlx::query("
+            INSERT INTO api_token_scopes (token_id, operation, resource)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING;
+        ")  .bind(token_id)
+            .bind(operation)
+            .bind(resource)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    render(&auth, settings, user_id, Some(token)).await
+}
+
+async fn revoke_token(
+    auth: Authentication,
+    extract::Path(id): extract::Path<i32>,
+) -> crate::Result<Redirect> {
+    let user_id = session_user(&auth)?;
+
+    let revoked = sqlx::query("
+        DELETE FROM api_tokens WHERE id = $1 AND user_id = $2;
+    ")  .bind(id)
+        .bind(user_id)
+        .execute(&auth.db)
+        .await?;
+    if revoked.rows_affected() == 0 {
+        return Err(crate::Error::NotFound);
+    }
+
+    Ok(Redirect::to("/auth/tokens"))
+}
+
+impl ApiToken {
+    fn last_used_ago(&self) -> Option<String> {
+        self.last_used_at.map(|at| timeago::Formatter::new().convert((time::OffsetDateTime::now_utc() - at).unsigned_abs()))
+    }
+
+    fn created_at_rfc2822(&self) -> String {
+        self.created_at
+            .format(&time::format_description::well_known::Rfc2822)
+            .expect("Couldn't convert token timestamp to RFC2822 string")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request, StatusCode}};
+    use tower::ServiceExt;
+
+    use crate::testing::{app, post, session_token, CSRF};
+
+    #[sqlx::test]
+    async fn tokens_act_within_their_scopes(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let session = session_token(&response);
+        sqlx::query("
+            INSERT INTO permissions (group_id, operation, resource, own_only)
+            SELECT id, 'update', resource, false
+            FROM groups, UNNEST(ARRAY['posts', 'tags']::RESOURCE[]) AS resource
+            WHERE name = 'users';
+        ").execute(&db).await.unwrap();
+        let post_id: i32 = sqlx::query_scalar("
+            INSERT INTO posts (md5, width, height, media_type, file_size, media_path, thumbnail_path)
+            VALUES ('0', 1, 1, 'image', 1, 'a.png', 'a.webp')
+            RETURNING id;
+        ").fetch_one(&db).await.unwrap();
+
+        let response = post(&app, "/api/auth/tokens", Some(&session), "name=script&scope=update:posts").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        // Forms on the page carry the CSRF token, which looks alike
+        let token = regex::Regex::new("[0-9a-f]{64}").unwrap()
+            .find_iter(&String::from_utf8_lossy(&body))
+            .find(|m| m.as_str() != CSRF)
+            .expect("The new token wasn't shown")
+            .as_str()
+            .to_string();
+
+        let with_token = |token: &str, uri: &str, body: &str| {
+            let request = Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let edit = format!("/api/posts/{post_id}/edit");
+
+        let response = with_token(&token, &edit, "rating=explicit").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        // The group may change tags, but the token may not
+        let response = with_token(&token, &edit, "tags=cat").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = with_token(&"0".repeat(64), &edit, "rating=safe").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Tokens can't be used to mint more, or to manage the account
+        let response = with_token(&token, "/api/auth/tokens", "name=another&scope=update:tags").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = with_token(&token, "/api/auth/account/delete", "password=hunter2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let token_id: i32 = sqlx::query_scalar("SELECT id FROM api_tokens;").fetch_one(&db).await.unwrap();
+        let response = post(&app, &format!("/api/auth/tokens/{token_id}/revoke"), Some(&session), "").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let response = with_token(&token, &edit, "rating=safe").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,774 @@
+//! Two-factor authentication with time-based one-time passwords (RFC 6238),
+//! as generated by authenticator apps, and single-use recovery codes for when
+//! the authenticator is lost.
+//!
+//! Signing in to an account with two-factor authentication takes two steps.
+//! A correct password starts a short-lived login challenge, and a correct code
+//! completes it. Members of a group that requires two-factor authentication
+//! but haven't set it up yet do so as the second step.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use aes_gcm::{aead::{Aead, AeadCore}, Aes256Gcm, Nonce};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use askama_axum::IntoResponse;
+use axum::{extract::State, response::{Redirect, Response}, routing::{get, post}, Form, Router};
+use axum_extra::extract::{cookie::{Cookie, SameSite}, CookieJar};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use uuid::Uuid;
+
+use crate::{
+    config::SecretKey,
+    error::ResultExt,
+    extractors::{Authentication, Client, Settings},
+    throttle::Throttles,
+    tokens::session_user,
+};
+
+/// Seconds each code is valid for
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from this many steps either side of now are accepted too, to allow
+/// for clocks drifting
+const SKEW: u64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODES: usize = 10;
+/// Minutes a login challenge lasts after the password is accepted
+const CHALLENGE_MINUTES: i32 = 5;
+/// Shown by authenticator apps next to the account
+const ISSUER: &str = "minibooru";
+
+#[derive(askama_axum::Template)]
+#[template(path = "two_factor.html")]
+struct TwoFactorTemplate {
+    signed_in: bool,
+    settings: Settings,
+    /// Where the code is submitted
+    action: &'static str,
+    /// Set while two-factor authentication is being set up
+    enrolment: Option<Enrolment>,
+    /// Recovery codes made when two-factor authentication was just enabled.
+    /// This is the only time they're shown.
+    recovery_codes: Option<Vec<String>>,
+    enabled: bool,
+    /// Whether the user's group requires two-factor authentication
+    required: bool,
+    /// Whether this is the second step of signing in, rather than settings
+    signing_in: bool,
+    /// Whether turning it off is confirmed by the password, rather than a code
+    has_password: bool,
+}
+
+struct Enrolment {
+    /// `otpauth://` URI for authenticator apps
+    uri: String,
+    /// The secret in base32, for typing in by hand
+    secret: String,
+    /// The URI as a QR code, in SVG
+    qr_code: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct Totp {
+    secret: Vec<u8>,
+    enabled: bool,
+    /// Whether `secret` is encrypted with the configured key
+    encrypted: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct CodeForm {
+    code: String,
+}
+
+#[derive(serde::Deserialize)]
+struct DisableForm {
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    code: String,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/auth/2fa", get(challenge_page))
+        .route("/auth/2fa/setup", get(setup_page))
+
+        .route("/api/auth/2fa", post(complete_sign_in))
+        .route("/api/auth/2fa/start", post(start_setup))
+        .route("/api/auth/2fa/setup", post(enable))
+        .route("/api/auth/2fa/disable", post(disable))
+}
+
+/// Computes the code for a time step (RFC 4226 section 5.3)
+pub fn code_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let truncated = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    truncated % 10u32.pow(DIGITS)
+}
+
+pub fn current_step() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / STEP_SECONDS
+}
+
+/// Returns the time step a code is valid for, if it's valid near `now`
+fn matching_step(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    (now.saturating_sub(SKEW)..=now + SKEW).find(|&step| code_at(secret, step) == code)
+}
+
+fn provisioning_uri(secret: &[u8], username: &str) -> String {
+    let secret = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, secret);
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+        issuer = percent_encode(ISSUER),
+        username = percent_encode(username),
+    )
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+// Named in full, as hmac's Mac has a `new` too
+fn cipher(key: &SecretKey) -> Aes256Gcm {
+    <Aes256Gcm as aes_gcm::KeyInit>::new(&key.0.into())
+}
+
+/// Encrypts a new secret for storage if there's a key to, returning it and
+/// whether it was encrypted
+fn seal(key: Option<&SecretKey>, secret: &[u8]) -> crate::Result<(Vec<u8>, bool)> {
+    let Some(key) = key else {
+        return Ok((secret.to_vec(), false));
+    };
+
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher(key)
+        .encrypt(&nonce, secret)
+        .map_err(|_| anyhow::anyhow!("Couldn't encrypt two-factor secret"))?;
+
+    Ok(([nonce.as_slice(), &ciphertext].concat(), true))
+}
+
+impl Totp {
+    /// Decrypts the secret if it's encrypted
+    fn secret(&self, key: Option<&SecretKey>) -> crate::Result<Vec<u8>> {
+        if !self.encrypted {
+            return Ok(self.secret.clone());
+        }
+
+        let key = key.ok_or_else(|| anyhow::anyhow!("accounts.totp-key is needed to read two-factor secrets"))?;
+        // 96-bit nonces, as Aes256Gcm uses
+        let (nonce, ciphertext) = self.secret.split_at_checked(12)
+            .ok_or_else(|| anyhow::anyhow!("Two-factor secret is too short"))?;
+        Ok(cipher(key)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Couldn't decrypt two-factor secret. Has accounts.totp-key changed?"))?)
+    }
+}
+
+/// Recovery codes are compared ignoring case and punctuation
+fn normalise_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn new_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Whether signing in to an account takes a second step, either because it
+/// has two-factor authentication or because its group requires it.
+pub async fn required(db: &sqlx::PgPool, user_id: Uuid) -> crate::Result<bool> {
+    Ok(sqlx::query_scalar("
+        SELECT groups.require_2fa OR EXISTS (
+            SELECT 1 FROM user_totp WHERE user_id = users.id AND enabled
+        )
+        FROM users
+        JOIN groups ON groups.id = users.group_id
+        WHERE users.id = $1;
+    ")  .bind(user_id)
+        .fetch_one(db)
+        .await?)
+}
+
+async fn group_requires(db: &sqlx::PgPool, user_id: Uuid) -> crate::Result<bool> {
+    Ok(sqlx::query_scalar("
+        SELECT groups.require_2fa
+        FROM users
+        JOIN groups ON groups.id = users.group_id
+        WHERE users.id = $1;
+    ")  .bind(user_id)
+        .fetch_one(db)
+        .await?)
+}
+
+/// Whether the user has finished setting up two-factor authentication
+pub async fn enabled(db: &sqlx::PgPool, user_id: Uuid) -> crate::Result<bool> {
+    Ok(totp(db, user_id).await?.is_some_and(|t| t.enabled))
+}
+
+async fn totp(db: &sqlx::PgPool, user_id: Uuid) -> crate::Result<Option<Totp>> {
+    Ok(sqlx::query_as("
+        SELECT secret, enabled, encrypted FROM user_totp WHERE user_id = $1;
+    ")  .bind(user_id)
+        .fetch_optional(db)
+        .await?)
+}
+
+/// Checks a code from the user's authenticator, or one of their recovery
+/// codes. Either can only be used once.
+pub async fn check_code(state: &crate::State, user_id: Uuid, code: &str) -> crate::Result<bool> {
+    let db = &state.db;
+    let code = code.trim();
+
+    if let Some(totp) = totp(db, user_id).await?.filter(|t| t.enabled) {
+        let secret = totp.secret(state.config.accounts.totp_key.as_ref())?;
+        if let Some(step) = matching_step(&secret, code, current_step()) {
+            // Only steps after the last accepted one count, so an overheard
+            // code can't be replayed
+            let accepted = sqlx::query("
+                UPDATE user_totp SET last_step = $2 WHERE user_id = $1 AND last_step < $2;
+            ")  .bind(user_id)
+                .bind(step as i64)
+                .execute(db)
+                .await?;
+
+            return Ok(accepted.rows_affected() == 1);
+        }
+    }
+
+    let used = sqlx::query("
+        DELETE FROM recovery_codes WHERE user_id = $1 AND hash = $2;
+    ")  .bind(user_id)
+        .bind(crate::tokens::hash(&normalise_recovery_code(code)))
+        .execute(db)
+        .await?;
+
+    Ok(used.rows_affected() == 1)
+}
+
+/// Starts setting up two-factor authentication. An unfinished setup's secret
+/// is kept, so an authenticator that already has it keeps working.
+async fn start_enrolment(state: &crate::State, user_id: Uuid) -> crate::Result<Enrolment> {
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    let (secret, encrypted) = seal(state.config.accounts.totp_key.as_ref(), &secret)?;
+
+    let started: Option<(Vec<u8>, bool, String)> = sqlx::query_as("
+        WITH totp AS (
+            INSERT INTO user_totp (user_id, secret, encrypted)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE SET secret = user_totp.secret
+            WHERE NOT user_totp.enabled
+            RETURNING secret, encrypted
+        )
+        SELECT totp.secret, totp.encrypted, users.username
+        FROM totp, users
+        WHERE users.id = $1;
+    ")  .bind(user_id)
+        .bind(secret)
+        .bind(encrypted)
+        .fetch_optional(&state.db)
+        .await?;
+    let Some((secret, encrypted, username)) = started else {
+        return Err(crate::Error::BadRequest(String::from("Two-factor authentication is already on")));
+    };
+    let totp = Totp { secret, enabled: false, encrypted };
+
+    enrolment(&totp.secret(state.config.accounts.totp_key.as_ref())?, &username)
+}
+
+/// Returns the setup the user started but hasn't finished, if any. Merely
+/// viewing the page doesn't start one.
+async fn pending_enrolment(state: &crate::State, user_id: Uuid) -> crate::Result<Option<Enrolment>> {
+    let pending: Option<(Vec<u8>, bool, String)> = sqlx::query_as("
+        SELECT user_totp.secret, user_totp.encrypted, users.username
+        FROM user_totp
+        JOIN users ON users.id = user_totp.user_id
+        WHERE user_totp.user_id = $1
+        AND NOT user_totp.enabled;
+    ")  .bind(user_id)
+        .fetch_optional(&state.db)
+        .await?;
+
+    pending
+        .map(|(secret, encrypted, username)| {
+            let totp = Totp { secret, enabled: false, encrypted };
+            enrolment(&totp.secret(state.config.accounts.totp_key.as_ref())?, &username)
+        })
+        .transpose()
+}
+
+fn enrolment(secret: &[u8], username: &str) -> crate::Result<Enrolment> {
+    let uri = provisioning_uri(secret, username);
+    let qr_code = qrcode::QrCode::new(&uri)
+        .map_err(|e| anyhow::anyhow!("Couldn't make QR code: {e}"))?
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    // The XML declaration doesn't belong inline in HTML
+    let qr_code = qr_code.find("<svg").map_or(qr_code.clone(), |start| qr_code[start..].to_string());
+
+    Ok(Enrolment {
+        uri,
+        secret: base32::encode(base32::Alphabet::Rfc4648 { padding: false }, secret),
+        qr_code,
+    })
+}
+
+/// Finishes setting up two-factor authentication if the code matches the new
+/// secret, returning fresh recovery codes.
+async fn finish_enrolment(state: &crate::State, user_id: Uuid, code: &str) -> crate::Result<Option<Vec<String>>> {
+    let mut tx = state.db.begin().await?;
+    let totp: Option<Totp> = sqlx::query_as("
+        SELECT secret, enabled, encrypted FROM user_totp WHERE user_id = $1 FOR UPDATE;
+    ")  .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let Some(totp) = totp.filter(|t| !t.enabled) else {
+        return Ok(None);
+    };
+    let secret = totp.secret(state.config.accounts.totp_key.as_ref())?;
+    let Some(step) = matching_step(&secret, code.trim(), current_step()) else {
+        return Ok(None);
+    };
+
+    sqlx::query("
+        UPDATE user_totp SET enabled = true, last_step = $2 WHERE user_id = $1;
+    ")  .bind(user_id)
+        .bind(step as i64)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("
+        DELETE FROM recovery_codes WHERE user_id = $1;
+    ")  .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| new_recovery_code()).collect();
+    for code in &codes {
+        sqlx::query("
+            INSERT INTO recovery_codes (user_id, hash) VALUES ($1, $2);
+        ")  .bind(user_id)
+            .bind(crate::tokens::hash(&normalise_recovery_code(code)))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(Some(codes))
+}
+
+/// Starts a login challenge for a user whose password was just accepted
+pub async fn start_challenge(
+    state: &crate::State,
+    user_id: Uuid,
+    remember: bool,
+    jar: CookieJar,
+) -> crate::Result<CookieJar> {
+    let token: Uuid = sqlx::query_scalar("
+        INSERT INTO login_challenges (user_id, remember)
+        VALUES ($1, $2)
+        RETURNING token;
+    ")  .bind(user_id)
+        .bind(remember)
+        .fetch_one(&state.db)
+        .await?;
+
+    let cookie = Cookie::build(("login_challenge", token.as_hyphenated().to_string()))
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .path("/")
+        .max_age(time::Duration::minutes(CHALLENGE_MINUTES.into()));
+
+    Ok(jar.add(cookie.build()))
+}
+
+/// Returns the token, user ID and username of the client's login challenge,
+/// and whether they asked to be remembered.
+async fn challenge(state: &crate::State, jar: &CookieJar) -> crate::Result<(Uuid, Uuid, String, bool)> {
+    let expired = || crate::Error::BadRequest(String::from("Sign-in expired, log in again"));
+    let token = jar.get("login_challenge")
+        .and_then(|c| Uuid::parse_str(c.value()).ok())
+        .ok_or_else(expired)?;
+
+    sqlx::query_as("
+        SELECT login_challenges.token, users.id, users.username, login_challenges.remember
+        FROM login_challenges
+        JOIN users ON users.id = login_challenges.user_id
+        WHERE login_challenges.token = $1
+        AND login_challenges.created_at > CURRENT_TIMESTAMP - make_interval(mins => $2);
+    ")  .bind(token)
+        .bind(CHALLENGE_MINUTES)
+        .fetch_one(&state.db)
+        .await
+        .on_no_rows(expired())
+}
+
+async fn challenge_page(
+    settings: Settings,
+    jar: CookieJar,
+    State(state): State<crate::State>,
+) -> crate::Result<impl IntoResponse> {
+    let (_, user_id, _, _) = challenge(&state, &jar).await?;
+    let enabled = enabled(&state.db, user_id).await?;
+
+    Ok(TwoFactorTemplate {
+        signed_in: false,
+        settings,
+        action: "/api/auth/2fa",
+        enrolment: if enabled { None } else { pending_enrolment(&state, user_id).await? },
+        recovery_codes: None,
+        enabled,
+        required: group_requires(&state.db, user_id).await?,
+        signing_in: true,
+        has_password: false,
+    })
+}
+
+/// Completes a login challenge with a code, or finishes setting up two-factor
+/// authentication if the user's group requires it and they hadn't.
+async fn complete_sign_in(
+    settings: Settings,
+    client: Client,
+    jar: CookieJar,
+    State(state): State<crate::State>,
+    Form(form): Form<CodeForm>,
+) -> crate::Result<Response> {
+    let (token, user_id, username, remember) = challenge(&state, &jar).await?;
+
+    let mut throttles = Throttles::new(&client, &username);
+    throttles.reserve(&state.db, &state.config.accounts).await?;
+
+    let recovery_codes = if enabled(&state.db, user_id).await? {
+        check_code(&state, user_id, &form.code).await?.then_some(None)
+    } else {
+        finish_enrolment(&state, user_id, &form.code).await?.map(Some)
+    };
+    let Some(recovery_codes) = recovery_codes else {
+        return Err(crate::Error::BadRequest(String::from("Incorrect code")));
+    };
+    throttles.succeed(&state.db).await?;
+
+    sqlx::query("
+        DELETE FROM login_challenges WHERE token = $1;
+    ")  .bind(token)
+        .execute(&state.db)
+        .await?;
+    let jar = jar.remove(Cookie::build("login_challenge").path("/").build());
+    let jar = crate::auth::add_sign_in_cookie(&state, user_id, &client, remember, jar).await?;
+
+    Ok(match recovery_codes {
+        Some(recovery_codes) => (jar, TwoFactorTemplate {
+            signed_in: true,
+            settings,
+            action: "/api/auth/2fa/setup",
+            enrolment: None,
+            recovery_codes: Some(recovery_codes),
+            enabled: true,
+            required: true,
+            signing_in: true,
+            has_password: false,
+        }).into_response(),
+        None => (jar, Redirect::to("/")).into_response(),
+    })
+}
+
+async fn setup_page(
+    auth: Authentication,
+    settings: Settings,
+    State(state): State<crate::State>,
+) -> crate::Result<impl IntoResponse> {
+    let user_id = session_user(&auth)?;
+    let enabled = enabled(&auth.db, user_id).await?;
+
+    Ok(TwoFactorTemplate {
+        signed_in: true,
+        settings,
+        action: "/api/auth/2fa/setup",
+        enrolment: if enabled { None } else { pending_enrolment(&state, user_id).await? },
+        recovery_codes: None,
+        enabled,
+        required: group_requires(&auth.db, user_id).await?,
+        signing_in: false,
+        has_password: crate::account::has_password(&auth.db, user_id).await?,
+    })
+}
+
+/// Generates the secret to set up two-factor authentication with, for a
+/// signed-in user or for one finishing signing in whose group requires it
+async fn start_setup(
+    auth: Authentication,
+    settings: Settings,
+    jar: CookieJar,
+    State(state): State<crate::State>,
+) -> crate::Result<impl IntoResponse> {
+    let (user_id, signing_in) = match session_user(&auth) {
+        Ok(user_id) => (user_id, false),
+        Err(_) => (challenge(&state, &jar).await?.1, true),
+    };
+
+    Ok(TwoFactorTemplate {
+        signed_in: !signing_in,
+        settings,
+        action: if signing_in { "/api/auth/2fa" } else { "/api/auth/2fa/setup" },
+        enrolment: Some(start_enrolment(&state, user_id).await?),
+        recovery_codes: None,
+        enabled: false,
+        required: group_requires(&state.db, user_id).await?,
+        signing_in,
+        has_password: false,
+    })
+}
+
+async fn enable(
+    auth: Authentication,
+    settings: Settings,
+    client: Client,
+    State(state): State<crate::State>,
+    Form(form): Form<CodeForm>,
+) -> crate::Result<impl IntoResponse> {
+    let user_id = session_user(&auth)?;
+    let username: String = sqlx::query_scalar("
+        SELECT username FROM users WHERE id = $1;
+    ")  .bind(user_id)
+        .fetch_one(&state.db)
+        .await?;
+
+    let mut throttles = Throttles::new(&client, &username);
+    throttles.reserve(&state.db, &state.config.accounts).await?;
+
+    let Some(recovery_codes) = finish_enrolment(&state, user_id, &form.code).await? else {
+        return Err(crate::Error::BadRequest(String::from("Incorrect code")));
+    };
+    throttles.release(&state.db).await?;
+
+    Ok(TwoFactorTemplate {
+        signed_in: true,
+        settings,
+        action: "/api/auth/2fa/setup",
+        enrolment: None,
+        recovery_codes: Some(recovery_codes),
+        enabled: true,
+        required: group_requires(&state.db, user_id).await?,
+        signing_in: false,
+        has_password: crate::account::has_password(&state.db, user_id).await?,
+    })
+}
+
+async fn disable(
+    auth: Authentication,
+    client: Client,
+    jar: CookieJar,
+    State(state): State<crate::State>,
+    Form(form): Form<DisableForm>,
+) -> crate::Result<Redirect> {
+    let user_id = session_user(&auth)?;
+
+    crate::account::confirm_password(&state, &client, &jar, user_id, form.password, &form.code).await?;
+    if group_requires(&state.db, user_id).await? {
+        return Err(crate::Error::BadRequest(String::from("Your group requires two-factor authentication")));
+    }
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query("
+        DELETE FROM user_totp WHERE user_id = $1;
+    ")  .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("
+        DELETE FROM recovery_codes WHERE user_id = $1;
+    ")  .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Redirect::to("/auth/2fa/setup"))
+}
+
+/// Ends the sessions of a group's members who haven't set up two-factor
+/// authentication, after the group starts requiring it. They're asked to set
+/// it up next time they sign in.
+pub async fn end_unprotected_sessions(
+    conn: &mut sqlx::PgConnection,
+    group_id: i32,
+) -> crate::Result<Vec<Uuid>> {
+    Ok(sqlx::query_scalar("
+        DELETE FROM sessions
+        USING users
+        WHERE users.id = sessions.user_id
+        AND users.group_id = $1
+        AND NOT EXISTS (
+            SELECT 1 FROM user_totp WHERE user_id = users.id AND enabled
+        )
+        RETURNING sessions.user_id;
+    ")  .bind(group_id)
+        .fetch_all(conn)
+        .await?)
+}
+
+/// Deletes expired login challenges
+pub async fn purge_challenges(db: &sqlx::PgPool) -> crate::Result<u64> {
+    Ok(sqlx::query("
+        DELETE FROM login_challenges
+        WHERE created_at < CURRENT_TIMESTAMP - make_interval(mins => $1);
+    ")  .bind(CHALLENGE_MINUTES)
+        .execute(db)
+        .await?
+        .rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request, StatusCode}};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::testing::{app, app_with, post, session_cookie, session_token, CSRF};
+
+    /// The SHA-1 secret from RFC 6238 appendix B
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn codes_match_rfc_6238() {
+        // The RFC's codes are 8 digits, these are their last 6
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ] {
+            assert_eq!(code_at(SECRET, time / STEP_SECONDS), code, "at {time}");
+        }
+    }
+
+    #[test]
+    fn codes_allow_clock_drift() {
+        let now = 1234567890 / STEP_SECONDS;
+        assert_eq!(matching_step(SECRET, "005924", now), Some(now));
+        assert_eq!(matching_step(SECRET, "005924", now + 1), Some(now));
+        assert_eq!(matching_step(SECRET, "005924", now + 2), None);
+        assert_eq!(matching_step(SECRET, "5924", now), None);
+    }
+
+    #[sqlx::test]
+    async fn login_with_two_factor_needs_code(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let secret = b"12345678901234567890";
+        sqlx::query("
+            INSERT INTO user_totp (user_id, secret, enabled) SELECT id, $1, true FROM users;
+        ").bind(&secret[..]).execute(&db).await.unwrap();
+
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(session_cookie(&response).is_none());
+        let challenge = response.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .find_map(|v| v.strip_prefix("login_challenge="))
+            .expect("No login challenge cookie was set")
+            .split(';').next().unwrap().to_string();
+
+        let complete = |code: String| {
+            let request = Request::post("/api/auth/2fa")
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .header(header::COOKIE, format!("login_challenge={challenge}; csrf={CSRF}"))
+                .body(Body::from(format!("code={code}&csrf_token={CSRF}")))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let code = code_at(secret, current_step());
+        let response = complete(format!("{:06}", (code + 1) % 1_000_000)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The right password alone doesn't forgive a wrong code
+        let response = post(&app, "/api/auth/login", None, "username=alice&password=hunter2").await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let failures: Option<i32> = sqlx::query_scalar("
+            SELECT MIN(failures) FROM login_throttles;
+        ").fetch_one(&db).await.unwrap();
+        assert_eq!(failures, Some(1));
+
+        let response = complete(format!("{code:06}")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        session_token(&response);
+    }
+
+    #[sqlx::test]
+    async fn setup_is_started_by_post_and_encrypted(db: sqlx::PgPool) {
+        let key = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let app = app_with(db.clone(), &format!("totp-key = \"{key}\""));
+        let response = post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let token = session_token(&response);
+
+        let setup_page = || {
+            let request = Request::get("/auth/2fa/setup")
+                .header(header::COOKIE, format!("session={token}"))
+                .body(Body::empty())
+                .unwrap();
+            async {
+                let response = app.clone().oneshot(request).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                String::from_utf8_lossy(&body).into_owned()
+            }
+        };
+        let totp_count = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM user_totp;").fetch_one(&db).await.unwrap()
+        };
+        let shown_secret = |page: &str| {
+            let start = page.find("Secret: <code>").expect("No secret was shown") + "Secret: <code>".len();
+            page[start..].split('<').next().unwrap().to_string()
+        };
+
+        assert!(!setup_page().await.contains("Secret:"));
+        assert_eq!(totp_count().await, 0);
+
+        let response = post(&app, "/api/auth/2fa/start", Some(&token), "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let secret = shown_secret(&String::from_utf8_lossy(&body));
+        // Unfinished setups keep their secret
+        assert_eq!(shown_secret(&setup_page().await), secret);
+        assert_eq!(totp_count().await, 1);
+
+        let secret = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, &secret).unwrap();
+        let stored: Vec<u8> = sqlx::query_scalar("SELECT secret FROM user_totp;").fetch_one(&db).await.unwrap();
+        assert!(!stored.windows(secret.len()).any(|w| w == secret));
+
+        let code = code_at(&secret, current_step());
+        let response = post(&app, "/api/auth/2fa/setup", Some(&token), &format!("code={code:06}")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(enabled(&db, sqlx::query_scalar("SELECT id FROM users;").fetch_one(&db).await.unwrap()).await.unwrap());
+
+        let response = post(&app, "/api/auth/2fa/start", Some(&token), "").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,232 @@
+use askama_axum::IntoResponse;
+use axum::{extract::{self, State}, routing::get, Json, Router};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    bans::{self, BanRecord, Restriction},
+    error::ResultExt,
+    extractors::{Authentication, Settings},
+    posts::QueriedPosts,
+    query::Blacklist,
+};
+
+/// How many uploads and favourites to show on a profile
+const RECENT_LIMIT: i64 = 12;
+
+#[derive(askama_axum::Template)]
+#[template(path = "user.html")]
+struct UserTemplate {
+    signed_in: bool,
+    settings: Settings,
+    user: Profile,
+    joined_at: String,
+    joined_ago: String,
+    /// The user's ban history, shown only to moderators
+    bans: Option<Vec<BanRecord>>,
+}
+
+#[derive(Serialize)]
+struct Profile {
+    #[serde(flatten)]
+    info: UserInformation,
+    recent_uploads: Vec<QueriedPosts>,
+    recent_favourites: Vec<QueriedPosts>,
+}
+
+#[derive(sqlx::FromRow, Serialize)]
+struct UserInformation {
+    pub id: Uuid,
+    pub username: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    pub group_name: String,
+    pub group_colour: String,
+    /* Statistics */
+    pub upload_count: i64,
+    pub favourite_count: i64,
+    pub tag_edit_count: i64,
+    pub likes_given: i64,
+    pub dislikes_given: i64,
+    pub upload_score: i64,
+}
+
+pub fn routes() -> Router<crate::State> {
+    Router::new()
+        .route("/users/:id", get(user_page))
+
+        .route("/api/users/:id", get(api_user))
+}
+
+/// Gets a user's profile. Their recent uploads and favourites are filtered by
+/// the viewer's settings, like any other list of posts.
+async fn get_profile(db: &sqlx::PgPool, id: Uuid, settings: &Settings, thumbnail_bound: u32) -> crate::Result<Profile> {
+    let info: UserInformation = sqlx::query_as("
+        SELECT
+            users.id,
+            users.username,
+            users.created_at,
+            groups.name AS group_name,
+            groups.colour AS group_colour,
+
+            (SELECT COUNT(*) FROM posts WHERE uploader_id = users.id) AS upload_count,
+            (SELECT COUNT(*) FROM user_favourites WHERE user_id = users.id) AS favourite_count,
+            (
+                SELECT COUNT(*)
+                FROM post_versions
+                WHERE editor_id = users.id
+                AND (cardinality(tags_added) > 0 OR cardinality(tags_removed) > 0)
+            ) AS tag_edit_count,
+            (SELECT COUNT(*) FROM user_votes WHERE user_id = users.id AND vote = 'like') AS likes_given,
+            (SELECT COUNT(*) FROM user_votes WHERE user_id = users.id AND vote = 'dislike') AS dislikes_given,
+            (
+                SELECT COALESCE(SUM(CASE WHEN user_votes.vote = 'like' THEN 1 ELSE -1 END), 0)
+                FROM user_votes
+                JOIN posts ON posts.id = user_votes.post_id
+                WHERE posts.uploader_id = users.id
+            ) AS upload_score
+        FROM users
+        JOIN groups ON groups.id = users.group_id
+        WHERE users.id = $1;
+    ")  .bind(id)
+        .fetch_one(db)
+        .await
+        .on_no_rows(crate::Error::NotFound)?;
+
+    let blacklist = Blacklist::new(&settings.blacklist);
+    let recent_uploads: Vec<QueriedPosts> = sqlx::query_as("
+        SELECT ('/posts/' || id)                    AS url,
+               thumbnail_url(state, thumbnail_path) AS thumbnail_path,
+               (thumbnail_dimensions(width, height, $3)).*
+        FROM posts
+        WHERE uploader_id = $1
+        AND rating <= $4
+        AND id NOT IN (SELECT blacklisted_posts($5, $6))
+        ORDER BY uploaded_at DESC
+        LIMIT $2;
+    ")  .bind(id)
+        .bind(RECENT_LIMIT)
+        .bind(thumbnail_bound as i32)
+        .bind(settings.max_rating)
+        .bind(&blacklist.expressions)
+        .bind(&blacklist.tags)
+        .fetch_all(db)
+        .await?;
+
+    // user_favourites has no timestamp, so the newest posts stand in for the
+    // most recently favourited
+    let recent_favourites: Vec<QueriedPosts> = sqlx::query_as("
+        SELECT ('/posts/' || posts.id)                    AS url,
+               thumbnail_url(posts.state, posts.thumbnail_path) AS thumbnail_path,
+               (thumbnail_dimensions(posts.width, posts.height, $3)).*
+        FROM user_favourites
+        JOIN posts ON posts.id = user_favourites.post_id
+        WHERE user_favourites.user_id = $1
+        AND posts.rating <= $4
+        AND posts.id NOT IN (SELECT blacklisted_posts($5, $6))
+        ORDER BY posts.uploaded_at DESC
+        LIMIT $2;
+    ")  .bind(id)
+        .bind(RECENT_LIMIT)
+        .bind(thumbnail_bound as i32)
+        .bind(settings.max_rating)
+        .bind(&blacklist.expressions)
+        .bind(&blacklist.tags)
+        .fetch_all(db)
+        .await?;
+
+    Ok(Profile { info, recent_uploads, recent_favourites })
+}
+
+async fn user_page(
+    auth: Authentication,
+    settings: Settings,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<Uuid>,
+) -> crate::Result<impl IntoResponse> {
+    let user = get_profile(&state.db, id, &settings, state.config.data.thumbnails.resolution).await?;
+
+    let joined_at = user.info.created_at
+        .format(&time::format_description::well_known::Rfc2822)
+        .expect("Couldn't convert join timestamp to RFC2822 string");
+    let joined_ago = timeago::Formatter::new().convert((time::OffsetDateTime::now_utc() - user.info.created_at).unsigned_abs());
+
+    let bans = if bans::is_moderator(&auth).await? {
+        Some(bans::history(&state.db, id).await?)
+    } else {
+        None
+    };
+
+    Ok(UserTemplate {
+        signed_in: auth.signed_in(),
+        settings,
+        user,
+        joined_at,
+        joined_ago,
+        bans,
+    })
+}
+
+async fn api_user(
+    settings: Settings,
+    State(state): State<crate::State>,
+    extract::Path(id): extract::Path<Uuid>,
+) -> crate::Result<Json<Profile>> {
+    Ok(Json(get_profile(&state.db, id, &settings, state.config.data.thumbnails.resolution).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request}};
+    use tower::ServiceExt;
+
+    use crate::testing::{app, post, session_token};
+
+    #[sqlx::test]
+    async fn profiles_follow_viewers_settings(db: sqlx::PgPool) {
+        let app = app(db.clone());
+        post(&app, "/api/auth/register", None, "username=alice&password=hunter2").await;
+        let response = post(&app, "/api/auth/register", None, "username=bob&password=hunter2").await;
+        let session = session_token(&response);
+        let alice: uuid::Uuid = sqlx::query_scalar("SELECT id FROM users WHERE username = 'alice';").fetch_one(&db).await.unwrap();
+
+        let posts: Vec<i32> = sqlx::query_scalar("
+            INSERT INTO posts (uploader_id, md5, width, height, media_type, file_size, media_path, thumbnail_path, rating)
+            SELECT $1, n::TEXT, 1, 1, 'image', 1, n || '.png', n || '.webp', rating::RATING
+            FROM UNNEST(ARRAY['safe', 'explicit', 'safe']) WITH ORDINALITY AS p(rating, n)
+            ORDER BY n
+            RETURNING id;
+        ").bind(alice).fetch_all(&db).await.unwrap();
+        sqlx::query("
+            WITH category AS (
+                INSERT INTO tag_categories (name, colour) VALUES ('general', '#000000') RETURNING id
+            ), tag AS (
+                INSERT INTO tags (name, category) SELECT 'cat', id FROM category RETURNING id
+            )
+            INSERT INTO post_tags (post_id, tag_id) SELECT $1, id FROM tag;
+        ").bind(posts[2]).execute(&db).await.unwrap();
+        sqlx::query("
+            INSERT INTO user_favourites (user_id, post_id) SELECT $1, UNNEST($2::INTEGER[]);
+        ").bind(alice).bind(&posts).execute(&db).await.unwrap();
+        sqlx::query("
+            INSERT INTO user_settings (user_id, page_size, max_rating, blacklist, thumbnail_size, theme, layout)
+            SELECT id, 20, 'questionable', '{cat}', 200, 'light', 'masonry' FROM users WHERE username = 'bob';
+        ").execute(&db).await.unwrap();
+
+        let request = Request::get(format!("/api/users/{alice}"))
+            .header(header::COOKIE, format!("session={session}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let profile: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        // The explicit post is above bob's rating, and the last is blacklisted
+        let shown = vec![serde_json::json!(format!("/posts/{}", posts[0]))];
+        for list in ["recent_uploads", "recent_favourites"] {
+            let urls: Vec<_> = profile[list].as_array().unwrap().iter().map(|p| p["url"].clone()).collect();
+            assert_eq!(urls, shown);
+        }
+        assert_eq!(profile["upload_count"], 3);
+    }
+}
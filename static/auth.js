@@ -8,7 +8,10 @@ form.addEventListener('submit', async (e) => {
     const formData = new URLSearchParams(new FormData(form));
     const options = {
         method: 'POST',
-        headers: new Headers({ 'Content-Type': 'application/x-www-form-urlencoded' }),
+        headers: new Headers({
+            'Content-Type': 'application/x-www-form-urlencoded',
+            'X-CSRF-Token': csrfToken(),
+        }),
         body: formData,
     };
 
@@ -25,6 +28,10 @@ form.addEventListener('submit', async (e) => {
         if (body !== '') {
             error.innerText += `: ${body}`;
         }
+    } else if (response.status === 202 && e.submitter.id === 'log-in') {
+        window.location.href = '/auth/2fa';
+    } else if (response.status === 202) {
+        error.innerText = await response.text();
     } else {
         window.location.href = '/';
     }
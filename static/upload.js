@@ -40,7 +40,7 @@ worker.onmessage = (event) => {
 };
 
 beginUpload.addEventListener('click', () => {
-    worker.postMessage({ type: 'begin-upload', });
+    worker.postMessage({ type: 'begin-upload', data: csrfToken() });
 });
 
 selectFiles.addEventListener('click', () => {
@@ -37,6 +37,8 @@ onmessage = async (event) => {
 
             const xhr = new XMLHttpRequest();
             xhr.open('POST', '/api/posts/upload', true);
+            // Workers can't read cookies, so the page passes the token along
+            xhr.setRequestHeader('X-CSRF-Token', data.data);
             xhr.send(fd);
 
             delete files[url];